@@ -0,0 +1,325 @@
+//! The `.loxc` format of compiled scripts, all the integers are little-endian.
+//!
+//! ```text
+//! file      := "LOXC" version:u32 function
+//! function  := name arity:u32 upvalues constants code lines
+//! name      := 0 | 1 string
+//! string    := len:u32 utf8-bytes
+//! upvalues  := count:u32 (is_local:u8 index:u8)*
+//! constants := count:u32 (0 f64 | 1 string | 2 function)*
+//! code      := count:u32 (discriminant:u8 operands)*
+//! lines     := count:u32 (line:u32 column:u32 count:u32)*
+//! ```
+//!
+//! The line table keeps only the positions of the runs, since the source the byte offsets of the spans point into
+//! is not stored.
+use crate::chunk::{Chunk, LineRun, OpCode};
+use crate::custom_error::BytecodeError;
+use crate::memory::Heap;
+use crate::object::{Function, UpvalueDesc};
+use crate::span::{FileId, Span};
+use crate::value::Value;
+use crate::verify::verify;
+
+const MAGIC: &[u8] = b"LOXC";
+/// Bumped whenever the format or the instruction set changes.
+pub const VERSION: u32 = 4;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+/// Functions nested deeper than this are rejected, so loading a malicious file can not overflow the native stack.
+const MAX_NESTING: usize = 256;
+
+/// A fixed-size value stored as little-endian bytes.
+pub trait Scalar: Sized {
+  fn read(reader: &mut Reader) -> Result<Self, BytecodeError>;
+  fn write(self, out: &mut Vec<u8>);
+}
+
+macro_rules! scalar {
+  ($($typ:ty),*) => {
+    $(
+      impl Scalar for $typ {
+        fn read(reader: &mut Reader) -> Result<Self, BytecodeError> {
+          let bytes = reader.bytes(std::mem::size_of::<$typ>())?;
+          Ok(<$typ>::from_le_bytes(bytes.try_into().unwrap()))
+        }
+
+        fn write(self, out: &mut Vec<u8>) {
+          out.extend_from_slice(&self.to_le_bytes());
+        }
+      }
+    )*
+  };
+}
+
+scalar!(u8, u16, u32, f64);
+
+/// Serialize the compiled script `function` into the `.loxc` format.
+pub fn write(function: &Function, heap: &Heap) -> Vec<u8> {
+  let mut out = MAGIC.to_vec();
+  VERSION.write(&mut out);
+  write_function(function, heap, &mut out);
+  out
+}
+
+fn write_len(len: usize, out: &mut Vec<u8>) {
+  u32::try_from(len).expect("Fatal: length overflows u32").write(out);
+}
+
+fn write_string(s: &str, out: &mut Vec<u8>) {
+  write_len(s.len(), out);
+  out.extend_from_slice(s.as_bytes());
+}
+
+fn write_function(function: &Function, heap: &Heap, out: &mut Vec<u8>) {
+  match &function.name {
+    Some(name) => {
+      out.push(1);
+      write_string(name, out);
+    }
+    None => out.push(0),
+  }
+  write_len(function.arity, out);
+  write_len(function.upvalues.len(), out);
+  for upvalue in &function.upvalues {
+    out.push(upvalue.is_local.into());
+    out.push(upvalue.index);
+  }
+
+  let chunk = &function.chunk;
+  write_len(chunk.constants().len(), out);
+  for constant in chunk.constants() {
+    match constant {
+      Value::Number(n) => {
+        out.push(TAG_NUMBER);
+        n.write(out);
+      }
+      Value::Str(s) => {
+        out.push(TAG_STRING);
+        write_string(heap.get::<String>(*s), out);
+      }
+      Value::Function(function) => {
+        out.push(TAG_FUNCTION);
+        write_function(heap.get(*function), heap, out);
+      }
+      _ => panic!("Fatal: unexpected constant {}", constant.to_string(heap)),
+    }
+  }
+  write_len(chunk.len(), out);
+  for op in chunk.code() {
+    write_opcode(*op, out);
+  }
+  write_len(chunk.lines().len(), out);
+  for run in chunk.lines() {
+    write_len(run.span.line, out);
+    write_len(run.span.column, out);
+    write_len(run.count, out);
+  }
+}
+
+fn write_opcode(op: OpCode, out: &mut Vec<u8>) {
+  use OpCode::*;
+  out.push(op.for_int_print() as u8);
+  match op {
+    Constant(i) | DefineGlobal(i) | GetGlobal(i) | SetGlobal(i) | GetLocal(i) | SetLocal(i) | Call(i) | Closure(i)
+    | GetUpvalue(i) | SetUpvalue(i) | Class(i) | GetProperty(i) | SetProperty(i) | Method(i) | GetSuper(i) => {
+      i.write(out)
+    }
+    ConstantLong(i) | DefineGlobalLong(i) | GetGlobalLong(i) | SetGlobalLong(i) | ClosureLong(i) | ClassLong(i)
+    | GetPropertyLong(i) | SetPropertyLong(i) | MethodLong(i) | GetSuperLong(i) => i.write(out),
+    Jump(offset) | JumpIfFalse(offset) | Loop(offset) => offset.write(out),
+    Invoke(i, arg_count) | SuperInvoke(i, arg_count) => {
+      i.write(out);
+      arg_count.write(out);
+    }
+    InvokeLong(i, arg_count) | SuperInvokeLong(i, arg_count) => {
+      i.write(out);
+      arg_count.write(out);
+    }
+    Return | Neg | Add | Sub | Mul | Div | True | False | Nil | Not | Greater | Less | Equal | NotEqual
+    | GreaterEqual | LessEqual | Print | Pop | CloseUpvalue | Inherit => {}
+  }
+}
+
+/// Deserialize a compiled script from `bytes`, the strings and nested functions are allocated on `heap`.  
+/// The script is checked by the verifier, so it is safe to execute.  
+/// The heap must not collect during loading, since the allocated objects are not rooted.  
+/// The spans in the script are set to point into `file`.
+pub fn read(bytes: &[u8], heap: &mut Heap, file: FileId) -> Result<Function, BytecodeError> {
+  let mut reader = Reader { bytes, offset: 0, file };
+  if reader.bytes(MAGIC.len()).ok() != Some(MAGIC) {
+    return Err(BytecodeError::new(0, "not a compiled lox file".into()));
+  }
+  let version: u32 = reader.read()?;
+  if version != VERSION {
+    return Err(BytecodeError::new(
+      MAGIC.len(),
+      format!("unsupported version {}, expected {}", version, VERSION),
+    ));
+  }
+  let function = reader.function(heap, 0)?;
+  if reader.offset != bytes.len() {
+    return Err(reader.error("trailing bytes after the script".into()));
+  }
+  verify(&function, heap).map_err(BytecodeError::Invalid)?;
+  Ok(function)
+}
+
+/// A cursor over the bytes of a `.loxc` file.
+pub struct Reader<'a> {
+  bytes: &'a [u8],
+  offset: usize,
+  file: FileId,
+}
+
+impl<'a> Reader<'a> {
+  pub fn read<T: Scalar>(&mut self) -> Result<T, BytecodeError> {
+    T::read(self)
+  }
+
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8], BytecodeError> {
+    let bytes = self
+      .offset
+      .checked_add(len)
+      .and_then(|end| self.bytes.get(self.offset..end))
+      .ok_or_else(|| self.error("unexpected end of file".into()))?;
+    self.offset += len;
+    Ok(bytes)
+  }
+
+  fn error(&self, msg: String) -> BytecodeError {
+    BytecodeError::new(self.offset, msg)
+  }
+
+  fn len(&mut self) -> Result<usize, BytecodeError> {
+    Ok(self.read::<u32>()? as usize)
+  }
+
+  fn string(&mut self) -> Result<String, BytecodeError> {
+    let len = self.len()?;
+    let start = self.offset;
+    let bytes = self.bytes(len)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| BytecodeError::new(start, "invalid utf-8 string".into()))
+  }
+
+  fn function(&mut self, heap: &mut Heap, depth: usize) -> Result<Function, BytecodeError> {
+    if depth > MAX_NESTING {
+      return Err(self.error("functions nested too deeply".into()));
+    }
+    let start = self.offset;
+    let name = match self.read::<u8>()? {
+      0 => None,
+      1 => Some(self.string()?),
+      tag => return Err(BytecodeError::new(start, format!("invalid function name tag {}", tag))),
+    };
+    let mut function = Function::new(name);
+    function.arity = self.len()?;
+    for _ in 0..self.len()? {
+      let start = self.offset;
+      let is_local = match self.read::<u8>()? {
+        0 => false,
+        1 => true,
+        tag => return Err(BytecodeError::new(start, format!("invalid upvalue kind {}", tag))),
+      };
+      let index = self.read()?;
+      function.upvalues.push(UpvalueDesc { is_local, index });
+    }
+
+    let mut constants = Vec::new();
+    for _ in 0..self.len()? {
+      let start = self.offset;
+      let constant = match self.read::<u8>()? {
+        TAG_NUMBER => Value::Number(self.read()?),
+        TAG_STRING => Value::Str(heap.intern(self.string()?)),
+        TAG_FUNCTION => {
+          let nested = self.function(heap, depth + 1)?;
+          Value::Function(heap.alloc(nested))
+        }
+        tag => return Err(BytecodeError::new(start, format!("unknown constant tag {}", tag))),
+      };
+      constants.push(constant);
+    }
+    let mut code = Vec::new();
+    for _ in 0..self.len()? {
+      let start = self.offset;
+      let tag = self.read::<u8>()?;
+      match OpCode::decode(tag.into(), self)? {
+        Some(op) => code.push(op),
+        None => return Err(BytecodeError::new(start, format!("unknown opcode {}", tag))),
+      }
+    }
+    let mut lines = Vec::new();
+    for _ in 0..self.len()? {
+      let (line, column) = (self.len()?, self.len()?);
+      let span = Span {
+        file: self.file,
+        line,
+        column,
+        ..Span::default()
+      };
+      lines.push(LineRun {
+        span,
+        count: self.len()?,
+      });
+    }
+
+    function.chunk = Chunk::from_parts(code, constants, lines);
+    Ok(function)
+  }
+}
+
+#[cfg(test)]
+mod bytecode_test {
+  use super::*;
+  use crate::compile::Compiler;
+
+  const PROGRAM: &str = "
+    fun counter(n) { fun next() { n = n + 1; return n; } return next; }
+    class A { init(x) { this.x = x; } get() { return this.x; } }
+    class B < A { get() { return super.get() * 2; } }
+    var c = counter(0);
+    for (var i = 0; i < 3; i = i + 1) { c(); }
+    var result = B(c()).get() + 0.5;
+    var s = \"lo\" + \"x\";";
+
+  fn compile(heap: &mut Heap) -> Vec<u8> {
+    let function = Compiler::new(PROGRAM.into(), heap).compile().unwrap();
+    write(&function, heap)
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let mut heap = Heap::new();
+    let bytes = compile(&mut heap);
+    let function = read(&bytes, &mut heap, FileId::default()).unwrap();
+    assert_eq!(write(&function, &heap), bytes);
+  }
+
+  #[test]
+  fn test_reject_corrupted() {
+    let mut heap = Heap::new();
+    let bytes = compile(&mut heap);
+    // every truncation is rejected rather than panicking
+    for len in 0..bytes.len() {
+      assert!(read(&bytes[..len], &mut heap, FileId::default()).is_err());
+    }
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(read(&trailing, &mut heap, FileId::default()).is_err());
+
+    let mut version = bytes.clone();
+    version[MAGIC.len()] += 1;
+    let error = read(&version, &mut heap, FileId::default()).err().unwrap();
+    assert!(error.to_string().contains("version"));
+
+    // flipping any byte must not lead to a panic, though it may still be a valid file
+    for index in 0..bytes.len() {
+      let mut corrupted = bytes.clone();
+      corrupted[index] ^= 0xff;
+      let _ = read(&corrupted, &mut heap, FileId::default());
+    }
+  }
+}
@@ -1,10 +1,14 @@
 use crate::def_opcode;
+use crate::memory::{Gc, Heap};
+use crate::span::Span;
 use crate::value::Value;
+use std::collections::HashMap;
 
 def_opcode!(
   OpCode,
   Return,
   Constant(u8),
+  ConstantLong(u32),
   Neg,
   Add,
   Sub,
@@ -16,41 +20,180 @@ def_opcode!(
   Not,
   Greater,
   Less,
-  Equal
+  Equal,
+  NotEqual,
+  GreaterEqual,
+  LessEqual,
+  Print,
+  Pop,
+  DefineGlobal(u8),
+  GetGlobal(u8),
+  SetGlobal(u8),
+  GetLocal(u8),
+  SetLocal(u8),
+  Jump(u16),
+  JumpIfFalse(u16),
+  Loop(u16),
+  Call(u8),
+  Closure(u8),
+  GetUpvalue(u8),
+  SetUpvalue(u8),
+  CloseUpvalue,
+  Class(u8),
+  GetProperty(u8),
+  SetProperty(u8),
+  Method(u8),
+  Invoke(u8, u8),
+  Inherit,
+  GetSuper(u8),
+  SuperInvoke(u8, u8),
+  DefineGlobalLong(u32),
+  GetGlobalLong(u32),
+  SetGlobalLong(u32),
+  ClosureLong(u32),
+  ClassLong(u32),
+  GetPropertyLong(u32),
+  SetPropertyLong(u32),
+  MethodLong(u32),
+  InvokeLong(u32, u8),
+  GetSuperLong(u32),
+  SuperInvokeLong(u32, u8)
 );
 
+impl OpCode {
+  /// The index of the constant the instruction refers to, if any.  
+  /// Every instruction with a u8 constant index has a `Long` variant with a wide one.
+  pub fn constant_index(&self) -> Option<usize> {
+    use OpCode::*;
+    match *self {
+      Constant(i)
+      | DefineGlobal(i)
+      | GetGlobal(i)
+      | SetGlobal(i)
+      | Closure(i)
+      | Class(i)
+      | GetProperty(i)
+      | SetProperty(i)
+      | Method(i)
+      | Invoke(i, _)
+      | GetSuper(i)
+      | SuperInvoke(i, _) => Some(i.into()),
+      ConstantLong(i)
+      | DefineGlobalLong(i)
+      | GetGlobalLong(i)
+      | SetGlobalLong(i)
+      | ClosureLong(i)
+      | ClassLong(i)
+      | GetPropertyLong(i)
+      | SetPropertyLong(i)
+      | MethodLong(i)
+      | InvokeLong(i, _)
+      | GetSuperLong(i)
+      | SuperInvokeLong(i, _) => Some(i as usize),
+      _ => None,
+    }
+  }
+
+  /// The same instruction referring to the constant `index` instead, in its short or wide variant.
+  pub fn with_constant_index(self, index: u32) -> OpCode {
+    use OpCode::*;
+    match self {
+      Constant(_) | ConstantLong(_) => wide(index, Constant, ConstantLong),
+      DefineGlobal(_) | DefineGlobalLong(_) => wide(index, DefineGlobal, DefineGlobalLong),
+      GetGlobal(_) | GetGlobalLong(_) => wide(index, GetGlobal, GetGlobalLong),
+      SetGlobal(_) | SetGlobalLong(_) => wide(index, SetGlobal, SetGlobalLong),
+      Closure(_) | ClosureLong(_) => wide(index, Closure, ClosureLong),
+      Class(_) | ClassLong(_) => wide(index, Class, ClassLong),
+      GetProperty(_) | GetPropertyLong(_) => wide(index, GetProperty, GetPropertyLong),
+      SetProperty(_) | SetPropertyLong(_) => wide(index, SetProperty, SetPropertyLong),
+      Method(_) | MethodLong(_) => wide(index, Method, MethodLong),
+      Invoke(_, arg_count) | InvokeLong(_, arg_count) => {
+        wide(index, |i| Invoke(i, arg_count), |i| InvokeLong(i, arg_count))
+      }
+      GetSuper(_) | GetSuperLong(_) => wide(index, GetSuper, GetSuperLong),
+      SuperInvoke(_, arg_count) | SuperInvokeLong(_, arg_count) => {
+        wide(index, |i| SuperInvoke(i, arg_count), |i| SuperInvokeLong(i, arg_count))
+      }
+      op => op,
+    }
+  }
+}
+
+/// Pick the instruction `short` if the constant `index` fits in a u8, otherwise its wide variant `long`.
+pub fn wide(index: u32, short: impl FnOnce(u8) -> OpCode, long: impl FnOnce(u32) -> OpCode) -> OpCode {
+  match u8::try_from(index) {
+    Ok(index) => short(index),
+    Err(_) => long(index),
+  }
+}
+
 /// Constant Pool used to store constant define by OP_CONSTANT,  
-/// The OP_CONSTANT could access the value it refers to by the u8 it carried as index.
+/// The OP_CONSTANT could access the value it refers to by the u8 it carried as index,
+/// constants beyond 255 are accessed by OP_CONSTANT_LONG instead.
 struct ConstantPool {
   constants: Vec<Value>,
+  /// Indices of the constants that could be shared, so an identical literal reuses the same slot.
+  shared: HashMap<SharedConstant, usize>,
+}
+
+/// The key of a deduplicated constant. Numbers are compared by bit pattern, and strings by handle,
+/// which is the same as by content since strings are interned.
+#[derive(PartialEq, Eq, Hash)]
+enum SharedConstant {
+  Number(u64),
+  Str(Gc<String>),
 }
 
 impl ConstantPool {
   pub fn new() -> Self {
-    Self { constants: Vec::new() }
+    Self {
+      constants: Vec::new(),
+      shared: HashMap::new(),
+    }
   }
 
   /// Add a constant to the pool, then return its index in the underlaying data buffer.  
-  /// This mothod is now use u8 as return value, which means that the index's max value is 255.
-  pub fn add_constant(&mut self, val: Value) -> u8 {
+  /// An identical number or string already in the pool is reused instead.
+  /// It is up to the caller to check whether the index fits in the operand of the instruction.
+  pub fn add_constant(&mut self, val: Value) -> usize {
+    let key = match val {
+      Value::Number(n) => Some(SharedConstant::Number(n.to_bits())),
+      Value::Str(s) => Some(SharedConstant::Str(s)),
+      _ => None,
+    };
+    if let Some(index) = key.as_ref().and_then(|key| self.shared.get(key)) {
+      return *index;
+    }
     self.constants.push(val);
-    (self.constants.len() - 1).try_into().unwrap()
+    let index = self.constants.len() - 1;
+    if let Some(key) = key {
+      self.shared.insert(key, index);
+    }
+    index
   }
 
   /// Retrievl the constant value via index.  
-  /// This method assert that the given index is always valid and do no bound-checking.  
-  /// Also, the index is represented by u8.
-  pub fn get_constant(&self, index: u8) -> Value {
-    unsafe { self.constants.get_unchecked(index as usize).clone() }
+  /// This method assert that the given index is always valid and do no bound-checking.
+  pub fn get_constant(&self, index: usize) -> Value {
+    unsafe { *self.constants.get_unchecked(index) }
   }
 }
 
+/// A run of consecutive opcodes coming from the same source position.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LineRun {
+  /// The span of the first opcode in the run, the others start at the same line and column.
+  pub span: Span,
+  /// The number of opcodes in the run.
+  pub count: usize,
+}
+
 /// Chunk used to store OpCodes and a constant pool.
 pub struct Chunk {
   chunks: Vec<OpCode>,
   constants: ConstantPool,
-  /// A parallel array store the line number the associated opcode comes from.
-  lines: Vec<u8>,
+  /// Run-length encoded source positions of the opcodes, the runs cover `chunks` in order.
+  lines: Vec<LineRun>,
 }
 
 impl Chunk {
@@ -62,6 +205,48 @@ impl Chunk {
     }
   }
 
+  /// Assemble a chunk from its parts as they are, which is used to load a chunk from a file.  
+  /// The parts are not checked to be consistent with each other.
+  pub fn from_parts(code: Vec<OpCode>, constants: Vec<Value>, lines: Vec<LineRun>) -> Self {
+    let mut pool = ConstantPool::new();
+    for constant in constants {
+      pool.constants.push(constant);
+    }
+    Self {
+      chunks: code,
+      constants: pool,
+      lines,
+    }
+  }
+
+  /// Drop the constants no instruction refers to, like the operands of folded expressions,
+  /// and renumber the references to the rest, keeping their order.
+  pub fn compact_constants(&mut self) {
+    let constants = &self.constants.constants;
+    let mut used = vec![false; constants.len()];
+    for op in &self.chunks {
+      if let Some(index) = op.constant_index() {
+        used[index] = true;
+      }
+    }
+    if used.iter().all(|used| *used) {
+      return;
+    }
+    let mut pool = ConstantPool::new();
+    let mut remap = vec![0; constants.len()];
+    for (index, constant) in constants.iter().enumerate() {
+      if used[index] {
+        remap[index] = pool.add_constant(*constant) as u32;
+      }
+    }
+    for op in &mut self.chunks {
+      if let Some(index) = op.constant_index() {
+        *op = op.with_constant_index(remap[index]);
+      }
+    }
+    self.constants = pool;
+  }
+
   /// Fetch the opcode pc point to.   
   /// **NOTE** the `pc` must be valid index or UB.
   pub fn fetch(&self, pc: usize) -> OpCode {
@@ -69,43 +254,248 @@ impl Chunk {
   }
 
   pub fn get_constant(&self, index: usize) -> Value {
-    unsafe { self.constants.constants.get_unchecked(index).clone() }
+    self.constants.get_constant(index)
   }
 
-  /// Add an OpCode into the underlying data buffer hold by Chunk.
-  pub fn write_chunk(&mut self, code: OpCode, line: u8) {
+  /// All the opcodes in the chunk.
+  pub fn code(&self) -> &[OpCode] {
+    &self.chunks
+  }
+
+  /// All the constants in the pool, which are traced by the garbage collector.
+  pub fn constants(&self) -> &[Value] {
+    &self.constants.constants
+  }
+
+  /// The number of opcodes in Chunk, which is also the index of the next opcode to be written.
+  pub fn len(&self) -> usize {
+    self.chunks.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.chunks.is_empty()
+  }
+
+  /// Replace the offset carried by the jump instruction at `index`.
+  pub fn patch_jump(&mut self, index: usize, offset: u16) {
+    match &mut self.chunks[index] {
+      OpCode::Jump(o) | OpCode::JumpIfFalse(o) => *o = offset,
+      _ => panic!("patching a non-jump instruction"),
+    }
+  }
+
+  /// Remove the opcodes after the first `len` ones, along with their line information.
+  pub fn truncate(&mut self, len: usize) {
+    let mut excess = self.chunks.len() - len;
+    self.chunks.truncate(len);
+    while excess > 0 {
+      let run = self.lines.last_mut().unwrap();
+      if run.count > excess {
+        run.count -= excess;
+        break;
+      }
+      excess -= run.count;
+      self.lines.pop();
+    }
+  }
+
+  /// Add an OpCode into the underlying data buffer hold by Chunk, along with the source position it comes from.
+  pub fn write_chunk(&mut self, code: OpCode, span: Span) {
     self.chunks.push(code);
-    self.lines.push(line);
+    match self.lines.last_mut() {
+      // the opcodes compiled from one token share a run
+      Some(run) if (run.span.line, run.span.column) == (span.line, span.column) => run.count += 1,
+      _ => self.lines.push(LineRun { span, count: 1 }),
+    }
   }
 
   /// Add a constant value into the constant pool it contains, then return the index of that constant in the pool.  
   /// This return value is what is carried by the OpCode Enum.
-  pub fn write_constant(&mut self, val: Value) -> u8 {
+  pub fn write_constant(&mut self, val: Value) -> usize {
     self.constants.add_constant(val)
   }
 
-  /// Display the opcodes in Chunk by lines, with additional information if exists.
-  pub fn disassembly(&self, title: &str) {
-    println!("== {} ==", title);
-    println!("{:16}LINE\n", "INSTRUCTION");
-    self
-      .chunks
+  /// Print the opcodes in Chunk to stderr, one per line as `index\tline\tOPCODE\toperands`.
+  /// Objects in the constant pool are looked up in `heap`.
+  pub fn disassembly(&self, title: &str, heap: &Heap) {
+    eprintln!("== {} ==", title);
+    let lines = self
+      .lines
       .iter()
-      .zip(&self.lines)
-      .for_each(|(ins, line)| self.disassembly_ins(ins, *line));
-    println!();
+      .flat_map(|run| std::iter::repeat_n(run.span.line, run.count));
+    for (index, (ins, line)) in self.chunks.iter().zip(lines).enumerate() {
+      eprintln!("{}\t{}\t{}\t{}", index, line, ins.name(), self.operands(ins, heap));
+    }
   }
 
-  pub fn disassembly_ins(&self, ins: &OpCode, line: u8) {
+  /// Describe the operands of `ins`, with the constants they refer to.
+  pub fn operands(&self, ins: &OpCode, heap: &Heap) -> String {
     use OpCode::*;
-    match ins {
-      // (code) (line number) (constant index) (constant value)
-      Constant(i) => println!("{}  {}  {}'{}", ins, line, i, self.constants.get_constant(*i)),
-      _ => println!("{}  {}", ins, line),
+    let constant = |i: usize| format!("{}'{}", i, self.get_constant(i).to_quoted_string(heap));
+    // (constant index)'(constant value)
+    if let Some(i) = ins.constant_index() {
+      let mut operands = constant(i);
+      match *ins {
+        // followed by the captured variables
+        Closure(_) | ClosureLong(_) => {
+          if let Value::Function(function) = self.get_constant(i) {
+            for upvalue in &heap.get(function).upvalues {
+              let kind = if upvalue.is_local { "local" } else { "upvalue" };
+              operands += &format!(" {} {}", kind, upvalue.index);
+            }
+          }
+        }
+        // followed by the argument count
+        Invoke(_, arg_count) | SuperInvoke(_, arg_count) | InvokeLong(_, arg_count) | SuperInvokeLong(_, arg_count) => {
+          operands += &format!(" ({} args)", arg_count)
+        }
+        _ => {}
+      }
+      return operands;
+    }
+    match *ins {
+      // (stack slot / upvalue index)
+      GetLocal(slot) | SetLocal(slot) | GetUpvalue(slot) | SetUpvalue(slot) => slot.to_string(),
+      // (argument count)
+      Call(arg_count) => arg_count.to_string(),
+      // (jump offset)
+      Jump(offset) | JumpIfFalse(offset) => format!("+{}", offset),
+      Loop(offset) => format!("-{}", offset),
+      _ => String::new(),
     }
   }
 
-  pub fn get_line_nu(&self, index: usize) -> u8 {
-    unsafe { *self.lines.get_unchecked(index) }
+  /// The source line of the opcode at `index`.
+  pub fn get_line_nu(&self, index: usize) -> usize {
+    self.get_span(index).line
+  }
+
+  /// The source code the opcode at `index` is compiled from.
+  pub fn get_span(&self, index: usize) -> Span {
+    let mut start = 0;
+    for run in &self.lines {
+      start += run.count;
+      if index < start {
+        return run.span;
+      }
+    }
+    panic!("Fatal: opcode index {} out of range", index)
+  }
+
+  /// The run-length encoded line table.
+  pub fn lines(&self) -> &[LineRun] {
+    &self.lines
+  }
+}
+
+#[cfg(test)]
+mod chunk_test {
+  use super::*;
+
+  #[test]
+  fn test_line_runs() {
+    let mut chunk = Chunk::new();
+    let span = |line, column| Span {
+      line,
+      column,
+      ..Span::default()
+    };
+    chunk.write_chunk(OpCode::Nil, span(1, 1));
+    chunk.write_chunk(OpCode::Return, span(1, 1));
+    chunk.write_chunk(OpCode::Nil, span(300, 7));
+    chunk.write_chunk(OpCode::Nil, span(1000, 2));
+    assert_eq!(chunk.lines().len(), 3);
+    assert_eq!(chunk.get_span(1), span(1, 1));
+    assert_eq!(chunk.get_span(2), span(300, 7));
+    assert_eq!(chunk.get_line_nu(3), 1000);
+    // a run is keyed by the position only
+    chunk.write_chunk(
+      OpCode::Pop,
+      Span {
+        end: 5,
+        ..span(1000, 2)
+      },
+    );
+    assert_eq!(chunk.lines().len(), 3);
+    assert_eq!(chunk.lines()[2].count, 2);
+    chunk.truncate(1);
+    assert_eq!(
+      chunk.lines(),
+      [LineRun {
+        span: span(1, 1),
+        count: 1
+      }]
+    );
+  }
+
+  #[test]
+  fn test_token_shares_run() {
+    let mut heap = Heap::new();
+    let source = "var a;\nprint a != a;";
+    let function = crate::compile::Compiler::with_opt_level(source.into(), &mut heap, crate::compile::OptLevel::O0)
+      .compile()
+      .unwrap();
+    let chunk = &function.chunk;
+    // `Equal` and `Not` both come from `!=`, the trailing `Nil` and `Return` from the end of the source
+    let ops: Vec<_> = chunk.code().iter().map(|op| op.name()).collect();
+    assert_eq!(
+      ops,
+      [
+        "Nil",
+        "DefineGlobal",
+        "GetGlobal",
+        "GetGlobal",
+        "Equal",
+        "Not",
+        "Print",
+        "Nil",
+        "Return"
+      ]
+    );
+    let runs: Vec<_> = chunk
+      .lines()
+      .iter()
+      .map(|run| (run.span.line, run.span.column, run.count))
+      .collect();
+    assert_eq!(
+      runs,
+      [
+        (1, 5, 1),
+        (1, 6, 1),
+        (2, 7, 1),
+        (2, 12, 1),
+        (2, 9, 2),
+        (2, 13, 1),
+        (2, 14, 2)
+      ]
+    );
+  }
+
+  #[test]
+  fn test_operands() {
+    let mut heap = Heap::new();
+    let mut chunk = Chunk::new();
+    let s = chunk.write_constant(Value::Str(heap.intern("a\tb".into())));
+    let operands = |ins| chunk.operands(&ins, &heap);
+    assert_eq!(operands(OpCode::Constant(s as u8)), "0'\"a\\tb\"");
+    assert_eq!(operands(OpCode::Invoke(0, 2)), "0'\"a\\tb\" (2 args)");
+    assert_eq!(operands(OpCode::GetGlobalLong(0)), "0'\"a\\tb\"");
+    assert_eq!(operands(OpCode::Loop(3)), "-3");
+    assert_eq!(operands(OpCode::Add), "");
+  }
+
+  #[test]
+  fn test_shared_constant() {
+    let mut heap = Heap::new();
+    let mut chunk = Chunk::new();
+    let s = heap.intern("s".into());
+    assert_eq!(chunk.write_constant(Value::Number(1.0)), 0);
+    assert_eq!(chunk.write_constant(Value::Str(s)), 1);
+    assert_eq!(chunk.write_constant(Value::Number(1.0)), 0);
+    assert_eq!(chunk.write_constant(Value::Str(heap.intern("s".into()))), 1);
+    // 0.0 and -0.0 are equal numbers but differ in bits
+    assert_eq!(chunk.write_constant(Value::Number(0.0)), 2);
+    assert_eq!(chunk.write_constant(Value::Number(-0.0)), 3);
+    assert_eq!(chunk.constants().len(), 4);
   }
 }
@@ -1,13 +1,16 @@
 use crate::chunk::*;
 use crate::custom_error::CompileError;
-use crate::scanner::Scanner;
+use crate::memory::Heap;
+use crate::object::{Function, UpvalueDesc};
+use crate::scanner::{parse_number, unescape, Scanner};
+use crate::span::{FileId, Span};
 use crate::token::*;
 use crate::value::Value;
-use std::rc::Rc;
 
 type CompileResult = Result<(), CompileError>;
 
-type ParseFn = Option<fn(&mut Compiler) -> CompileResult>;
+/// The `bool` argument indicates whether the expression being parsed could be an assignment target.
+type ParseFn = Option<fn(&mut Compiler, bool) -> CompileResult>;
 
 struct ParseRule {
   prefix: ParseFn,
@@ -16,23 +19,111 @@ struct ParseRule {
 }
 
 const TOKEN_NUM: usize = 39;
+/// Locals are addressed by a u8 stack slot.
+const MAX_LOCALS: usize = 256;
+/// Upvalues are addressed by a u8 index.
+const MAX_UPVALUES: usize = 256;
+/// Both the number of parameters and arguments are carried by a u8.
+const MAX_ARITY: usize = 255;
+/// Constants referred by the `Long` instructions are addressed by a 24-bit index.
+const MAX_CONSTANTS: usize = 1 << 24;
 
-pub struct Compiler {
-  chunk: Chunk,
+/// How much the compiler optimizes the bytecode.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum OptLevel {
+  /// Emit the bytecode as it is written.
+  O0,
+  /// Fold constant expressions and combine instruction pairs.
+  #[default]
+  O1,
+}
+
+/// A local variable living in a VM stack slot.
+struct Local {
+  name: String,
+  /// The scope depth where the local is declared, `None` if it is declared but not yet initialized.
+  depth: Option<usize>,
+  /// Whether the local is captured by any closure, if so it must be closed rather than popped.
+  is_captured: bool,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum FunctionType {
+  Function,
+  Initializer,
+  Method,
+  Script,
+}
+
+/// The compiling state of a single function body.
+struct FunctionState {
+  function: Function,
+  typ: FunctionType,
+  /// Locals in scope, the index of a local is exactly its stack slot relative to the call frame.
+  locals: Vec<Local>,
+  /// Variables of enclosing functions captured by this function.
+  upvalues: Vec<UpvalueDesc>,
+  scope_depth: usize,
+  /// The largest index some jump lands on. The optimizer never rewrites instructions before it,
+  /// otherwise the jump would land in the middle of the rewritten code.
+  jump_target: usize,
+}
+
+impl FunctionState {
+  fn new(typ: FunctionType, name: Option<String>) -> Self {
+    Self {
+      function: Function::new(name),
+      typ,
+      // The slot 0 is claimed by the function being called, or the receiver for methods.
+      locals: vec![Local {
+        name: match typ {
+          FunctionType::Method | FunctionType::Initializer => "this".into(),
+          _ => String::new(),
+        },
+        depth: Some(0),
+        is_captured: false,
+      }],
+      upvalues: Vec::new(),
+      scope_depth: 0,
+      jump_target: 0,
+    }
+  }
+}
+
+/// The compiling state of a class body.
+struct ClassState {
+  has_superclass: bool,
+}
+
+pub struct Compiler<'a> {
   current: Token,
   previous: Token,
   scanner: Scanner,
   rules: [ParseRule; TOKEN_NUM],
+  /// The functions being compiled, the innermost one is on the top.
+  states: Vec<FunctionState>,
+  /// The classes being compiled, the innermost one is on the top.
+  classes: Vec<ClassState>,
+  /// Where the string and function constants are allocated.  
+  /// The compiler never triggers a collection, so these objects need not be rooted during compiling.
+  heap: &'a mut Heap,
+  opt_level: OptLevel,
+  /// Print the disassembly of every compiled function to stderr.
+  print_code: bool,
 }
 
-impl Compiler {
-  pub fn new(source: String) -> Self {
+impl<'a> Compiler<'a> {
+  pub fn new(source: String, heap: &'a mut Heap) -> Self {
+    Self::with_opt_level(source, heap, OptLevel::default())
+  }
+
+  pub fn with_opt_level(source: String, heap: &'a mut Heap, opt_level: OptLevel) -> Self {
     // FIXME This look-up table is extreamely ugly and terrible.
     let rules: [ParseRule; TOKEN_NUM] = [
       ParseRule {
         prefix: Some(grouping),
-        infix: None,
-        precedence: Precedence::None,
+        infix: Some(call),
+        precedence: Precedence::Call,
       }, // LParen
       ParseRule {
         prefix: None,
@@ -56,8 +147,8 @@ impl Compiler {
       }, // Comma
       ParseRule {
         prefix: None,
-        infix: None,
-        precedence: Precedence::None,
+        infix: Some(dot),
+        precedence: Precedence::Call,
       }, // Dot
       ParseRule {
         prefix: Some(unary),
@@ -125,7 +216,7 @@ impl Compiler {
         precedence: Precedence::Comparison,
       }, // Le
       ParseRule {
-        prefix: None,
+        prefix: Some(variable),
         infix: None,
         precedence: Precedence::None,
       }, // Ident
@@ -141,8 +232,8 @@ impl Compiler {
       }, // Num
       ParseRule {
         prefix: None,
-        infix: None,
-        precedence: Precedence::None,
+        infix: Some(and),
+        precedence: Precedence::And,
       }, // And
       ParseRule {
         prefix: None,
@@ -181,8 +272,8 @@ impl Compiler {
       }, // Nil
       ParseRule {
         prefix: None,
-        infix: None,
-        precedence: Precedence::None,
+        infix: Some(or),
+        precedence: Precedence::Or,
       }, // Or
       ParseRule {
         prefix: None,
@@ -195,12 +286,12 @@ impl Compiler {
         precedence: Precedence::None,
       }, // Ret
       ParseRule {
-        prefix: None,
+        prefix: Some(super_),
         infix: None,
         precedence: Precedence::None,
       }, // Super
       ParseRule {
-        prefix: None,
+        prefix: Some(this),
         infix: None,
         precedence: Precedence::None,
       }, // This
@@ -226,25 +317,49 @@ impl Compiler {
       }, // Eof
     ];
     Self {
-      chunk: Chunk::new(),
       current: Token::default(),
       previous: Token::default(),
       scanner: Scanner::new(source),
       rules,
+      states: vec![FunctionState::new(FunctionType::Script, None)],
+      classes: Vec::new(),
+      heap,
+      opt_level,
+      print_code: false,
     }
   }
 
-  fn advance(&mut self) {
+  /// Set the file the source is registered as, which the spans in errors and the compiled code point into.
+  pub fn set_file(&mut self, file: FileId) {
+    self.scanner.set_file(file);
+  }
+
+  pub fn set_print_code(&mut self, print_code: bool) {
+    self.print_code = print_code;
+  }
+
+  /// The state of the innermost function being compiled.
+  fn state(&self) -> &FunctionState {
+    self.states.last().unwrap()
+  }
+
+  fn state_mut(&mut self) -> &mut FunctionState {
+    self.states.last_mut().unwrap()
+  }
+
+  /// The chunk of the innermost function being compiled.
+  fn chunk(&self) -> &Chunk {
+    &self.state().function.chunk
+  }
+
+  fn chunk_mut(&mut self) -> &mut Chunk {
+    &mut self.state_mut().function.chunk
+  }
+
+  fn advance(&mut self) -> CompileResult {
     std::mem::swap(&mut self.previous, &mut self.current);
-    loop {
-      match self.scanner.scan_token() {
-        Ok(t) => {
-          self.current = t;
-          return;
-        }
-        Err(e) => eprintln!("{}", e),
-      }
-    }
+    self.current = self.scanner.scan_token()?;
+    Ok(())
   }
 
   fn get_rule(&self, typ: TokenType) -> &ParseRule {
@@ -255,9 +370,452 @@ impl Compiler {
     self.parse_precedence(Precedence::Assign)
   }
 
+  /// Check whether the current token is of type `typ`, without consuming it.
+  fn check(&self, typ: TokenType) -> bool {
+    self.current.typ == typ
+  }
+
+  /// If the current token is of type `typ`, consume it and return true.
+  fn is_match(&mut self, typ: TokenType) -> Result<bool, CompileError> {
+    if !self.check(typ) {
+      return Ok(false);
+    }
+    self.advance()?;
+    Ok(true)
+  }
+
+  fn declaration(&mut self) -> CompileResult {
+    if self.is_match(TokenType::Class)? {
+      self.class_declaration()
+    } else if self.is_match(TokenType::Fun)? {
+      self.fun_declaration()
+    } else if self.is_match(TokenType::Var)? {
+      self.var_declaration()
+    } else {
+      self.statement()
+    }
+  }
+
+  fn class_declaration(&mut self) -> CompileResult {
+    self.consume(TokenType::Ident, "expect class name".into())?;
+    let class_name = self.previous_literal();
+    let name_constant = self.identifier_constant(&class_name)?;
+    self.declare_variable()?;
+    self.emit_byte(wide(name_constant, OpCode::Class, OpCode::ClassLong));
+    self.define_variable(name_constant);
+
+    self.classes.push(ClassState { has_superclass: false });
+    if self.is_match(TokenType::Lt)? {
+      self.consume(TokenType::Ident, "expect superclass name".into())?;
+      let superclass_name = self.previous_literal();
+      self.named_variable(&superclass_name, false)?;
+      if superclass_name == class_name {
+        return Err(self.raise_at_previous("a class can't inherit from itself".into()));
+      }
+      // store the superclass in a synthetic local `super`, so that methods capture it as an upvalue
+      self.begin_scope();
+      self.add_local("super".into())?;
+      self.define_variable(0);
+      self.named_variable(&class_name, false)?;
+      self.emit_byte(OpCode::Inherit);
+      self.classes.last_mut().unwrap().has_superclass = true;
+    }
+    // load the class onto the stack so that methods could be bound to it
+    self.named_variable(&class_name, false)?;
+    self.consume(TokenType::LBrace, "expect '{' before class body".into())?;
+    while !self.check(TokenType::RBrace) && !self.check(TokenType::Eof) {
+      self.method()?;
+    }
+    self.consume(TokenType::RBrace, "expect '}' after class body".into())?;
+    self.emit_byte(OpCode::Pop);
+    if self.classes.pop().unwrap().has_superclass {
+      self.end_scope();
+    }
+    Ok(())
+  }
+
+  fn method(&mut self) -> CompileResult {
+    self.consume(TokenType::Ident, "expect method name".into())?;
+    let name = self.previous_literal();
+    let constant = self.identifier_constant(&name)?;
+    let typ = if name == "init" {
+      FunctionType::Initializer
+    } else {
+      FunctionType::Method
+    };
+    self.function(typ)?;
+    self.emit_byte(wide(constant, OpCode::Method, OpCode::MethodLong));
+    Ok(())
+  }
+
+  fn fun_declaration(&mut self) -> CompileResult {
+    let global = self.parse_variable("expect function name".into())?;
+    // a function could refer to itself in its body, so mark it initialized eagerly
+    self.mark_initialized();
+    self.function(FunctionType::Function)?;
+    self.define_variable(global);
+    Ok(())
+  }
+
+  /// Compile the parameters and body of a function, then emit it as a constant.
+  fn function(&mut self, typ: FunctionType) -> CompileResult {
+    let name = self.previous_literal();
+    self.states.push(FunctionState::new(typ, Some(name)));
+    self.begin_scope();
+    self.consume(TokenType::LParen, "expect '(' after function name".into())?;
+    if !self.check(TokenType::RParen) {
+      loop {
+        self.state_mut().function.arity += 1;
+        if self.state().function.arity > MAX_ARITY {
+          return Err(self.raise_at_current(format!("can't have more than {} parameters", MAX_ARITY)));
+        }
+        let param = self.parse_variable("expect parameter name".into())?;
+        self.define_variable(param);
+        if !self.is_match(TokenType::Comma)? {
+          break;
+        }
+      }
+    }
+    self.consume(TokenType::RParen, "expect ')' after parameters".into())?;
+    self.consume(TokenType::LBrace, "expect '{' before function body".into())?;
+    self.block()?;
+    let function = self.end_compile();
+    let function = self.heap.alloc(function);
+    let index = self.make_const(Value::Function(function))?;
+    self.emit_byte(wide(index, OpCode::Closure, OpCode::ClosureLong));
+    Ok(())
+  }
+
+  fn var_declaration(&mut self) -> CompileResult {
+    let global = self.parse_variable("expect variable name".into())?;
+    if self.is_match(TokenType::Equal)? {
+      self.expression()?;
+    } else {
+      self.emit_byte(OpCode::Nil);
+    }
+    self.consume(TokenType::Semicolon, "expect ';' after variable declaration".into())?;
+    self.define_variable(global);
+    Ok(())
+  }
+
+  /// Consume an identifier and declare it.  
+  /// For a global, store its name into the constant pool and return the index,
+  /// for a local, the returned index is meaningless.
+  fn parse_variable(&mut self, msg: String) -> Result<u32, CompileError> {
+    self.consume(TokenType::Ident, msg)?;
+    self.declare_variable()?;
+    if self.state().scope_depth > 0 {
+      return Ok(0);
+    }
+    self.identifier_constant(&self.previous_literal())
+  }
+
+  /// Record the local variable named by `self.previous`, do nothing for globals.
+  fn declare_variable(&mut self) -> CompileResult {
+    if self.state().scope_depth == 0 {
+      return Ok(());
+    }
+    let name = self.previous_literal();
+    let state = self.state();
+    let redeclared = state
+      .locals
+      .iter()
+      .rev()
+      .take_while(|local| local.depth.is_none_or(|depth| depth >= state.scope_depth))
+      .any(|local| local.name == name);
+    if redeclared {
+      return Err(self.raise_at_previous("already a variable with this name in this scope".into()));
+    }
+    self.add_local(name)
+  }
+
+  fn add_local(&mut self, name: String) -> CompileResult {
+    if self.state().locals.len() == MAX_LOCALS {
+      return Err(self.raise_at_previous("too many local variables in function".into()));
+    }
+    self.state_mut().locals.push(Local {
+      name,
+      depth: None,
+      is_captured: false,
+    });
+    Ok(())
+  }
+
+  /// Make the variable available. A local is already on the stack, so just mark it initialized.
+  fn define_variable(&mut self, global: u32) {
+    if self.state().scope_depth > 0 {
+      self.mark_initialized();
+      return;
+    }
+    self.emit_byte(wide(global, OpCode::DefineGlobal, OpCode::DefineGlobalLong));
+  }
+
+  fn mark_initialized(&mut self) {
+    let state = self.state_mut();
+    if state.scope_depth == 0 {
+      return;
+    }
+    if let Some(local) = state.locals.last_mut() {
+      local.depth = Some(state.scope_depth);
+    }
+  }
+
+  /// Find the stack slot of local variable `name` in the function `self.states[depth]`,
+  /// return `None` if it is not a local.
+  fn resolve_local(&self, depth: usize, name: &str) -> Result<Option<u8>, CompileError> {
+    let locals = &self.states[depth].locals;
+    match locals.iter().rposition(|local| local.name == name) {
+      Some(slot) if locals[slot].depth.is_none() => {
+        Err(self.raise_at_previous("can't read local variable in its own initializer".into()))
+      }
+      Some(slot) => Ok(Some(slot as u8)),
+      None => Ok(None),
+    }
+  }
+
+  /// Find the upvalue index of variable `name` captured by the function `self.states[depth]`,
+  /// return `None` if it is a global.  
+  /// The variable is looked up from the directly enclosing function outward, every function
+  /// in between captures it as an upvalue as well.
+  fn resolve_upvalue(&mut self, depth: usize, name: &str) -> Result<Option<u8>, CompileError> {
+    if depth == 0 {
+      return Ok(None);
+    }
+    if let Some(slot) = self.resolve_local(depth - 1, name)? {
+      self.states[depth - 1].locals[slot as usize].is_captured = true;
+      return self.add_upvalue(depth, slot, true).map(Some);
+    }
+    if let Some(index) = self.resolve_upvalue(depth - 1, name)? {
+      return self.add_upvalue(depth, index, false).map(Some);
+    }
+    Ok(None)
+  }
+
+  /// Add an upvalue to the function `self.states[depth]` if it is not captured yet, return its index.
+  fn add_upvalue(&mut self, depth: usize, index: u8, is_local: bool) -> Result<u8, CompileError> {
+    let upvalue = UpvalueDesc { is_local, index };
+    let upvalues = &self.states[depth].upvalues;
+    if let Some(i) = upvalues.iter().position(|u| *u == upvalue) {
+      return Ok(i as u8);
+    }
+    if upvalues.len() == MAX_UPVALUES {
+      return Err(self.raise_at_previous("too many closure variables in function".into()));
+    }
+    self.states[depth].upvalues.push(upvalue);
+    Ok((self.states[depth].upvalues.len() - 1) as u8)
+  }
+
+  fn begin_scope(&mut self) {
+    self.state_mut().scope_depth += 1;
+  }
+
+  /// Leave the scope and pop all the locals declared in it.
+  fn end_scope(&mut self) {
+    self.state_mut().scope_depth -= 1;
+    let out_of_scope = |state: &FunctionState| {
+      state
+        .locals
+        .last()
+        .is_some_and(|local| local.depth.unwrap() > state.scope_depth)
+    };
+    while out_of_scope(self.state()) {
+      if self.state().locals.last().unwrap().is_captured {
+        self.emit_byte(OpCode::CloseUpvalue);
+      } else {
+        self.emit_byte(OpCode::Pop);
+      }
+      self.state_mut().locals.pop();
+    }
+  }
+
+  /// Compile the arguments of a call, return the number of them.
+  fn argument_list(&mut self) -> Result<u8, CompileError> {
+    let mut arg_count = 0;
+    if !self.check(TokenType::RParen) {
+      loop {
+        self.expression()?;
+        if arg_count == MAX_ARITY {
+          return Err(self.raise_at_previous(format!("can't have more than {} arguments", MAX_ARITY)));
+        }
+        arg_count += 1;
+        if !self.is_match(TokenType::Comma)? {
+          break;
+        }
+      }
+    }
+    self.consume(TokenType::RParen, "expect ')' after arguments".into())?;
+    Ok(arg_count as u8)
+  }
+
+  fn block(&mut self) -> CompileResult {
+    while !self.check(TokenType::RBrace) && !self.check(TokenType::Eof) {
+      self.declaration()?;
+    }
+    self.consume(TokenType::RBrace, "expect '}' after block".into())
+  }
+
+  /// Store the identifier `name` into the constant pool as a string.
+  fn identifier_constant(&mut self, name: &str) -> Result<u32, CompileError> {
+    let name = self.heap.intern(name.to_string());
+    self.make_const(Value::Str(name))
+  }
+
+  fn previous_literal(&self) -> String {
+    self.previous.get_literal(self.scanner.source())
+  }
+
+  /// Emit the bytecode to get or set the variable `name`.
+  fn named_variable(&mut self, name: &str, can_assign: bool) -> CompileResult {
+    let depth = self.states.len() - 1;
+    let (get_op, set_op) = if let Some(slot) = self.resolve_local(depth, name)? {
+      (OpCode::GetLocal(slot), OpCode::SetLocal(slot))
+    } else if let Some(index) = self.resolve_upvalue(depth, name)? {
+      (OpCode::GetUpvalue(index), OpCode::SetUpvalue(index))
+    } else {
+      let arg = self.identifier_constant(name)?;
+      (
+        wide(arg, OpCode::GetGlobal, OpCode::GetGlobalLong),
+        wide(arg, OpCode::SetGlobal, OpCode::SetGlobalLong),
+      )
+    };
+    if can_assign && self.is_match(TokenType::Equal)? {
+      self.expression()?;
+      self.emit_byte(set_op);
+    } else {
+      self.emit_byte(get_op);
+    }
+    Ok(())
+  }
+
+  fn statement(&mut self) -> CompileResult {
+    if self.is_match(TokenType::Print)? {
+      self.print_statement()
+    } else if self.is_match(TokenType::Ret)? {
+      self.return_statement()
+    } else if self.is_match(TokenType::If)? {
+      self.if_statement()
+    } else if self.is_match(TokenType::While)? {
+      self.while_statement()
+    } else if self.is_match(TokenType::For)? {
+      self.for_statement()
+    } else if self.is_match(TokenType::LBrace)? {
+      self.begin_scope();
+      self.block()?;
+      self.end_scope();
+      Ok(())
+    } else {
+      self.expression_statement()
+    }
+  }
+
+  fn print_statement(&mut self) -> CompileResult {
+    self.expression()?;
+    self.consume(TokenType::Semicolon, "expect ';' after value".into())?;
+    self.emit_byte(OpCode::Print);
+    Ok(())
+  }
+
+  fn return_statement(&mut self) -> CompileResult {
+    if self.state().typ == FunctionType::Script {
+      return Err(self.raise_at_previous("can't return from top-level code".into()));
+    }
+    if self.is_match(TokenType::Semicolon)? {
+      self.emit_return();
+    } else {
+      if self.state().typ == FunctionType::Initializer {
+        return Err(self.raise_at_previous("can't return a value from an initializer".into()));
+      }
+      self.expression()?;
+      self.consume(TokenType::Semicolon, "expect ';' after return value".into())?;
+      self.emit_byte(OpCode::Return);
+    }
+    Ok(())
+  }
+
+  fn if_statement(&mut self) -> CompileResult {
+    self.consume(TokenType::LParen, "expect '(' after 'if'".into())?;
+    self.expression()?;
+    self.consume(TokenType::RParen, "expect ')' after condition".into())?;
+    let then_jump = self.emit_jump(OpCode::JumpIfFalse(0));
+    self.emit_byte(OpCode::Pop);
+    self.statement()?;
+    let else_jump = self.emit_jump(OpCode::Jump(0));
+    self.patch_jump(then_jump)?;
+    self.emit_byte(OpCode::Pop);
+    if self.is_match(TokenType::Else)? {
+      self.statement()?;
+    }
+    self.patch_jump(else_jump)
+  }
+
+  fn while_statement(&mut self) -> CompileResult {
+    let loop_start = self.jump_target();
+    self.consume(TokenType::LParen, "expect '(' after 'while'".into())?;
+    self.expression()?;
+    self.consume(TokenType::RParen, "expect ')' after condition".into())?;
+    let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0));
+    self.emit_byte(OpCode::Pop);
+    self.statement()?;
+    self.emit_loop(loop_start)?;
+    self.patch_jump(exit_jump)?;
+    self.emit_byte(OpCode::Pop);
+    Ok(())
+  }
+
+  /// Desugar `for` into a while loop, the increment clause is jumped over at the first time
+  /// and executed after the body.
+  fn for_statement(&mut self) -> CompileResult {
+    self.begin_scope();
+    self.consume(TokenType::LParen, "expect '(' after 'for'".into())?;
+    if self.is_match(TokenType::Semicolon)? {
+      // no initializer
+    } else if self.is_match(TokenType::Var)? {
+      self.var_declaration()?;
+    } else {
+      self.expression_statement()?;
+    }
+
+    let mut loop_start = self.jump_target();
+    let mut exit_jump = None;
+    if !self.is_match(TokenType::Semicolon)? {
+      self.expression()?;
+      self.consume(TokenType::Semicolon, "expect ';' after loop condition".into())?;
+      exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse(0)));
+      self.emit_byte(OpCode::Pop);
+    }
+
+    if !self.is_match(TokenType::RParen)? {
+      let body_jump = self.emit_jump(OpCode::Jump(0));
+      let increment_start = self.jump_target();
+      self.expression()?;
+      self.emit_byte(OpCode::Pop);
+      self.consume(TokenType::RParen, "expect ')' after for clauses".into())?;
+      self.emit_loop(loop_start)?;
+      loop_start = increment_start;
+      self.patch_jump(body_jump)?;
+    }
+
+    self.statement()?;
+    self.emit_loop(loop_start)?;
+    if let Some(exit_jump) = exit_jump {
+      self.patch_jump(exit_jump)?;
+      self.emit_byte(OpCode::Pop);
+    }
+    self.end_scope();
+    Ok(())
+  }
+
+  /// Evaluate the expression and discard the result.
+  fn expression_statement(&mut self) -> CompileResult {
+    self.expression()?;
+    self.consume(TokenType::Semicolon, "expect ';' after expression".into())?;
+    self.emit_byte(OpCode::Pop);
+    Ok(())
+  }
+
   fn consume(&mut self, typ: TokenType, msg: String) -> CompileResult {
     if self.current.typ == typ {
-      self.advance();
+      self.advance()?;
       Ok(())
     } else {
       Err(self.raise_at_current(msg))
@@ -266,13 +824,13 @@ impl Compiler {
 
   /// Raise a `ParseError` from current token.
   fn raise_at_current(&self, msg: String) -> CompileError {
-    CompileError::new(self.current.line, self.current.get_literal(self.scanner.source()), msg)
+    CompileError::new(self.current.span, self.current.get_literal(self.scanner.source()), msg)
   }
 
   /// Raise a `ParseError` from previous token.
   fn raise_at_previous(&self, msg: String) -> CompileError {
     CompileError::new(
-      self.previous.line,
+      self.previous.span,
       self.previous.get_literal(self.scanner.source()),
       msg,
     )
@@ -280,37 +838,43 @@ impl Compiler {
 
   // Parse the op whose precedence is equal to or higher the `precedence`
   fn parse_precedence(&mut self, precedence: Precedence) -> CompileResult {
-    self.advance();
+    self.advance()?;
     let prefix_rule = self
       .get_rule(self.previous.typ)
       .prefix
       .ok_or_else(|| self.raise_at_previous("expect expression".into()))?;
-    prefix_rule(self)?;
+    // only the expression whose precedence is low enough could be an assignment target
+    let can_assign = precedence <= Precedence::Assign;
+    prefix_rule(self, can_assign)?;
     let mut infix_rule;
     while precedence <= self.get_rule(self.current.typ).precedence {
-      self.advance();
+      self.advance()?;
       infix_rule = self.get_rule(self.previous.typ).infix.expect("unreachable");
-      infix_rule(self)?;
+      infix_rule(self, can_assign)?;
+    }
+    if can_assign && self.is_match(TokenType::Equal)? {
+      return Err(self.raise_at_previous("invalid assignment target".into()));
     }
     Ok(())
   }
 
-  /// Do compile, return an `CompileResult` for error handling.
-  pub fn compile(&mut self) -> CompileResult {
-    self.advance();
-    self.expression()?;
-    self.consume(TokenType::Eof, "expect end of expression".into())?;
-    self.end_compile();
-    Ok(())
-  }
-
-  pub fn return_chunk(self) -> Chunk {
-    self.chunk
+  /// Do compile, return the top-level script as a `Function`, or a `CompileError` for error handling.
+  pub fn compile(mut self) -> Result<Function, CompileError> {
+    self.advance()?;
+    while !self.is_match(TokenType::Eof)? {
+      self.declaration()?;
+    }
+    Ok(self.end_compile())
   }
 
   /// Emit single bytecode to `self.chunk`
   pub fn emit_byte(&mut self, typ: OpCode) {
-    self.chunk.write_chunk(typ, self.previous.line as u8);
+    self.emit_byte_at(typ, self.previous.span);
+  }
+
+  /// Emit single bytecode to `self.chunk`, pointing at `span` rather than the previous token.
+  fn emit_byte_at(&mut self, typ: OpCode, span: Span) {
+    self.chunk_mut().write_chunk(typ, span);
   }
 
   /// Emit two bytecodes to `self.chunk`
@@ -320,83 +884,243 @@ impl Compiler {
   }
 
   /// Store a constant to constant pool in chunk, then emit a
-  /// OP_CONST to chunk.
-  pub fn emit_const(&mut self, value: Value) {
-    let offset = self.make_const(value);
-    self.emit_byte(OpCode::Constant(offset))
+  /// OP_CONST to chunk, or OP_CONST_LONG if the index does not fit in a u8.
+  pub fn emit_const(&mut self, value: Value) -> CompileResult {
+    self.emit_const_at(value, self.previous.span)
+  }
+
+  fn emit_const_at(&mut self, value: Value, span: Span) -> CompileResult {
+    let index = self.make_const(value)?;
+    self.emit_byte_at(wide(index, OpCode::Constant, OpCode::ConstantLong), span);
+    Ok(())
   }
 
-  pub fn make_const(&mut self, value: Value) -> u8 {
-    self.chunk.write_constant(value)
+  /// Store a constant referred by an instruction, such as a variable name, and return its index.
+  pub fn make_const(&mut self, value: Value) -> Result<u32, CompileError> {
+    let index = self.chunk_mut().write_constant(value);
+    if index >= MAX_CONSTANTS {
+      return Err(self.raise_at_previous("too many constants in one chunk".into()));
+    }
+    Ok(index as u32)
+  }
+
+  /// Emit a unary operator, the operation is done at compile time if the operand is a constant.
+  /// `Not` after a comparison is combined into a single instruction.
+  fn emit_unary(&mut self, op: OpCode, span: Span) -> CompileResult {
+    if let Some(operand) = self.folding_operand(0) {
+      let folded = match (op, operand) {
+        (OpCode::Neg, Value::Number(n)) => Some(Value::Number(-n)),
+        (OpCode::Not, value) => Some(Value::Boolean(value.is_false())),
+        _ => None,
+      };
+      if let Some(folded) = folded {
+        return self.replace_tail(1, folded, span);
+      }
+    }
+    if let OpCode::Not = op {
+      let combined = match self.rewritable_tail(1) {
+        Some(OpCode::Equal) => Some(OpCode::NotEqual),
+        Some(OpCode::Greater) => Some(OpCode::LessEqual),
+        Some(OpCode::Less) => Some(OpCode::GreaterEqual),
+        _ => None,
+      };
+      if let Some(combined) = combined {
+        let len = self.chunk().len();
+        self.chunk_mut().truncate(len - 1);
+        self.emit_byte_at(combined, span);
+        return Ok(());
+      }
+    }
+    self.emit_byte_at(op, span);
+    Ok(())
+  }
+
+  /// Emit a binary operator, the operation is done at compile time if both operands are constants.
+  fn emit_binary(&mut self, op: OpCode, span: Span) -> CompileResult {
+    if let (Some(lhs), Some(rhs)) = (self.folding_operand(1), self.folding_operand(0)) {
+      use Value::{Boolean, Number, Str};
+      let folded = match (op, lhs, rhs) {
+        (OpCode::Add, Number(a), Number(b)) => Some(Number(a + b)),
+        (OpCode::Add, Str(a), Str(b)) => {
+          let s = format!("{}{}", self.heap.get(a), self.heap.get(b));
+          Some(Str(self.heap.intern(s)))
+        }
+        (OpCode::Sub, Number(a), Number(b)) => Some(Number(a - b)),
+        (OpCode::Mul, Number(a), Number(b)) => Some(Number(a * b)),
+        (OpCode::Div, Number(a), Number(b)) => Some(Number(a / b)),
+        (OpCode::Greater, Number(a), Number(b)) => Some(Boolean(a > b)),
+        (OpCode::Less, Number(a), Number(b)) => Some(Boolean(a < b)),
+        (OpCode::Equal, a, b) => Some(Boolean(a.equals(&b))),
+        // leave the operand type errors to the runtime
+        _ => None,
+      };
+      if let Some(folded) = folded {
+        return self.replace_tail(2, folded, span);
+      }
+    }
+    self.emit_byte_at(op, span);
+    Ok(())
+  }
+
+  /// The last `count`-th instruction, if the optimizer is allowed to rewrite it and everything after it.
+  fn rewritable_tail(&self, count: usize) -> Option<OpCode> {
+    if self.opt_level == OptLevel::O0 {
+      return None;
+    }
+    let index = self.chunk().len().checked_sub(count)?;
+    if index < self.state().jump_target {
+      return None;
+    }
+    Some(self.chunk().fetch(index))
   }
 
-  fn end_compile(&mut self) {
+  /// The value loaded by the last `back + 1`-th instruction, if it loads a constant that could be folded.
+  fn folding_operand(&self, back: usize) -> Option<Value> {
+    match self.rewritable_tail(back + 1)? {
+      OpCode::Constant(i) => Some(self.chunk().get_constant(i as usize)),
+      OpCode::ConstantLong(i) => Some(self.chunk().get_constant(i as usize)),
+      OpCode::True => Some(Value::Boolean(true)),
+      OpCode::False => Some(Value::Boolean(false)),
+      OpCode::Nil => Some(Value::Nil),
+      _ => None,
+    }
+  }
+
+  /// Replace the last `count` instructions by a single one loading `value`, pointing at `span`.
+  fn replace_tail(&mut self, count: usize, value: Value, span: Span) -> CompileResult {
+    let len = self.chunk().len();
+    self.chunk_mut().truncate(len - count);
+    match value {
+      Value::Boolean(true) => self.emit_byte_at(OpCode::True, span),
+      Value::Boolean(false) => self.emit_byte_at(OpCode::False, span),
+      Value::Nil => self.emit_byte_at(OpCode::Nil, span),
+      value => return self.emit_const_at(value, span),
+    }
+    Ok(())
+  }
+
+  /// Finish the innermost function, pop its state and return the compiled `Function`.
+  fn end_compile(&mut self) -> Function {
     self.emit_return();
-    self.chunk.disassembly("CHUNK");
+    let state = self.states.pop().unwrap();
+    let mut function = state.function;
+    function.upvalues = state.upvalues;
+    // folding leaves the constants of the folded operands behind
+    function.chunk.compact_constants();
+    #[cfg(feature = "trace")]
+    if self.print_code {
+      let title = function.name.as_deref().unwrap_or("<script>");
+      function.chunk.disassembly(title, self.heap);
+    }
+    function
+  }
+
+  /// Emit a jump instruction with a placeholder offset, return its index for back-patching.
+  fn emit_jump(&mut self, jump: OpCode) -> usize {
+    self.emit_byte(jump);
+    self.chunk().len() - 1
+  }
+
+  /// Fill the jump instruction at `index` with the offset to the next instruction to be emitted.
+  fn patch_jump(&mut self, index: usize) -> CompileResult {
+    let offset: u16 = (self.chunk().len() - index - 1)
+      .try_into()
+      .map_err(|_| self.raise_at_previous("too much code to jump over".into()))?;
+    self.chunk_mut().patch_jump(index, offset);
+    self.jump_target();
+    Ok(())
+  }
+
+  /// Mark the index of the next instruction as a jump target and return it.
+  fn jump_target(&mut self) -> usize {
+    let index = self.chunk().len();
+    self.state_mut().jump_target = index;
+    index
+  }
+
+  /// Emit a `Loop` instruction jumping backward to `loop_start`.
+  fn emit_loop(&mut self, loop_start: usize) -> CompileResult {
+    let offset: u16 = (self.chunk().len() - loop_start + 1)
+      .try_into()
+      .map_err(|_| self.raise_at_previous("loop body too large".into()))?;
+    self.emit_byte(OpCode::Loop(offset));
+    Ok(())
   }
 
+  /// Emit an implicit `return nil;`, or return the instance in slot 0 for an initializer.
   fn emit_return(&mut self) {
-    self.emit_byte(OpCode::Return);
+    if self.state().typ == FunctionType::Initializer {
+      self.emit_bytes((OpCode::GetLocal(0), OpCode::Return));
+    } else {
+      self.emit_bytes((OpCode::Nil, OpCode::Return));
+    }
   }
 }
 
 /// Parse grouping expression, emiting bytecode
-fn grouping(compiler: &mut Compiler) -> CompileResult {
+fn grouping(compiler: &mut Compiler, _can_assign: bool) -> CompileResult {
   compiler.expression()?;
   compiler.consume(TokenType::RParen, "expect ')' after expression".into())
 }
 
 /// Parse unary expression, emiting bytecode
-fn unary(compiler: &mut Compiler) -> CompileResult {
+fn unary(compiler: &mut Compiler, _can_assign: bool) -> CompileResult {
   use TokenType::*;
   let op_type = compiler.previous.typ;
+  // the operator is emitted after its operand, but errors should point at the operator
+  let span = compiler.previous.span;
   compiler.parse_precedence(Precedence::Unary)?;
   match op_type {
-    Minus => compiler.emit_byte(OpCode::Neg),
-    Bang => compiler.emit_byte(OpCode::Not),
-    _ => {}
+    Minus => compiler.emit_unary(OpCode::Neg, span),
+    Bang => compiler.emit_unary(OpCode::Not, span),
+    _ => Ok(()),
   }
-  Ok(())
 }
 
 /// Parse number literal, emiting const bytecode.  
 /// This function will panic immediatelly if the char silce `compiler.previous` point to
 /// is NOT a meaningful number, which should not happen after correct scanning.
-fn number(compiler: &mut Compiler) -> CompileResult {
-  let value: f64 = compiler
-    .previous
-    .get_literal(compiler.scanner.source())
-    .parse()
-    .expect("Fatal: number literal convert error");
-  compiler.emit_const(Value::Number(value));
-  Ok(())
+fn number(compiler: &mut Compiler, _can_assign: bool) -> CompileResult {
+  let literal = compiler.previous.get_literal(compiler.scanner.source());
+  let value = parse_number(&literal).expect("Fatal: number literals are checked by the scanner");
+  compiler.emit_const(Value::Number(value))
 }
 
 /// Parse binary expression
-fn binary(compiler: &mut Compiler) -> CompileResult {
+fn binary(compiler: &mut Compiler, _can_assign: bool) -> CompileResult {
   use TokenType::*;
   let op_type = compiler.previous.typ;
+  let span = compiler.previous.span;
   let rule = compiler.get_rule(op_type);
   // parse the expresion whose precedence is higher than current op
   // because the binary opration is left associated.
   compiler.parse_precedence(Precedence::higher(&rule.precedence))?;
   match op_type {
-    Plus => compiler.emit_byte(OpCode::Add),
-    Minus => compiler.emit_byte(OpCode::Sub),
-    Star => compiler.emit_byte(OpCode::Mul),
-    Slash => compiler.emit_byte(OpCode::Div),
-    EEqual => compiler.emit_byte(OpCode::Equal),
-    EBang => compiler.emit_bytes((OpCode::Equal, OpCode::Not)),
-    Le => compiler.emit_bytes((OpCode::Greater, OpCode::Not)),
-    Ge => compiler.emit_bytes((OpCode::Less, OpCode::Not)),
-    Lt => compiler.emit_byte(OpCode::Less),
-    Gt => compiler.emit_byte(OpCode::Greater),
-    _ => {}
+    Plus => compiler.emit_binary(OpCode::Add, span),
+    Minus => compiler.emit_binary(OpCode::Sub, span),
+    Star => compiler.emit_binary(OpCode::Mul, span),
+    Slash => compiler.emit_binary(OpCode::Div, span),
+    EEqual => compiler.emit_binary(OpCode::Equal, span),
+    // the pairs are combined into a single instruction by the optimizer
+    EBang => {
+      compiler.emit_binary(OpCode::Equal, span)?;
+      compiler.emit_unary(OpCode::Not, span)
+    }
+    Le => {
+      compiler.emit_binary(OpCode::Greater, span)?;
+      compiler.emit_unary(OpCode::Not, span)
+    }
+    Ge => {
+      compiler.emit_binary(OpCode::Less, span)?;
+      compiler.emit_unary(OpCode::Not, span)
+    }
+    Lt => compiler.emit_binary(OpCode::Less, span),
+    Gt => compiler.emit_binary(OpCode::Greater, span),
+    _ => Ok(()),
   }
-  Ok(())
 }
 
-fn literal(compiler: &mut Compiler) -> CompileResult {
+fn literal(compiler: &mut Compiler, _can_assign: bool) -> CompileResult {
   use TokenType::*;
   match compiler.previous.typ {
     True => compiler.emit_byte(OpCode::True),
@@ -407,22 +1131,285 @@ fn literal(compiler: &mut Compiler) -> CompileResult {
   Ok(())
 }
 
-fn string(compiler: &mut Compiler) -> CompileResult {
-  let s = compiler.previous.get_literal(compiler.scanner.source());
-  compiler.emit_const(Value::Str(Rc::new(s)));
+fn string(compiler: &mut Compiler, _can_assign: bool) -> CompileResult {
+  let token = &compiler.previous;
+  let raw = &compiler.scanner.source()[token.span.start + 1..token.span.end - 1];
+  let s = unescape(raw).expect("Fatal: escape sequences are checked by the scanner");
+  let s = compiler.heap.intern(s);
+  compiler.emit_const(Value::Str(s))
+}
+
+fn variable(compiler: &mut Compiler, can_assign: bool) -> CompileResult {
+  let name = compiler.previous_literal();
+  compiler.named_variable(&name, can_assign)
+}
+
+/// Parse property access, assignment or method invocation after `.`.
+fn dot(compiler: &mut Compiler, can_assign: bool) -> CompileResult {
+  compiler.consume(TokenType::Ident, "expect property name after '.'".into())?;
+  let name = compiler.identifier_constant(&compiler.previous_literal())?;
+  if can_assign && compiler.is_match(TokenType::Equal)? {
+    compiler.expression()?;
+    compiler.emit_byte(wide(name, OpCode::SetProperty, OpCode::SetPropertyLong));
+  } else if compiler.is_match(TokenType::LParen)? {
+    let arg_count = compiler.argument_list()?;
+    compiler.emit_byte(wide(
+      name,
+      |i| OpCode::Invoke(i, arg_count),
+      |i| OpCode::InvokeLong(i, arg_count),
+    ));
+  } else {
+    compiler.emit_byte(wide(name, OpCode::GetProperty, OpCode::GetPropertyLong));
+  }
   Ok(())
 }
 
+/// Parse `super.method` access or `super.method(...)` call, the superclass is looked up via the synthetic `super` variable.
+fn super_(compiler: &mut Compiler, _can_assign: bool) -> CompileResult {
+  match compiler.classes.last() {
+    None => return Err(compiler.raise_at_previous("can't use 'super' outside of a class".into())),
+    Some(class) if !class.has_superclass => {
+      return Err(compiler.raise_at_previous("can't use 'super' in a class with no superclass".into()))
+    }
+    _ => {}
+  }
+  compiler.consume(TokenType::Dot, "expect '.' after 'super'".into())?;
+  compiler.consume(TokenType::Ident, "expect superclass method name".into())?;
+  let name = compiler.identifier_constant(&compiler.previous_literal())?;
+  compiler.named_variable("this", false)?;
+  if compiler.is_match(TokenType::LParen)? {
+    let arg_count = compiler.argument_list()?;
+    compiler.named_variable("super", false)?;
+    compiler.emit_byte(wide(
+      name,
+      |i| OpCode::SuperInvoke(i, arg_count),
+      |i| OpCode::SuperInvokeLong(i, arg_count),
+    ));
+  } else {
+    compiler.named_variable("super", false)?;
+    compiler.emit_byte(wide(name, OpCode::GetSuper, OpCode::GetSuperLong));
+  }
+  Ok(())
+}
+
+/// Parse `this`, which is just a local variable living in slot 0 of methods.
+fn this(compiler: &mut Compiler, _can_assign: bool) -> CompileResult {
+  if compiler.classes.is_empty() {
+    return Err(compiler.raise_at_previous("can't use 'this' outside of a class".into()));
+  }
+  compiler.named_variable("this", false)
+}
+
+/// Parse call expression, the callee is already on the stack.
+fn call(compiler: &mut Compiler, _can_assign: bool) -> CompileResult {
+  let arg_count = compiler.argument_list()?;
+  compiler.emit_byte(OpCode::Call(arg_count));
+  Ok(())
+}
+
+/// Parse `and` expression. If the left operand is falsey, skip the right one and leave the left as the result.
+fn and(compiler: &mut Compiler, _can_assign: bool) -> CompileResult {
+  let end_jump = compiler.emit_jump(OpCode::JumpIfFalse(0));
+  compiler.emit_byte(OpCode::Pop);
+  compiler.parse_precedence(Precedence::And)?;
+  compiler.patch_jump(end_jump)
+}
+
+/// Parse `or` expression. If the left operand is truthy, skip the right one and leave the left as the result.
+fn or(compiler: &mut Compiler, _can_assign: bool) -> CompileResult {
+  let else_jump = compiler.emit_jump(OpCode::JumpIfFalse(0));
+  let end_jump = compiler.emit_jump(OpCode::Jump(0));
+  compiler.patch_jump(else_jump)?;
+  compiler.emit_byte(OpCode::Pop);
+  compiler.parse_precedence(Precedence::Or)?;
+  compiler.patch_jump(end_jump)
+}
+
 #[cfg(test)]
 mod compile_test {
   use super::*;
+  use crate::verify::verify;
+
+  fn compile(source: &str) -> Result<Function, CompileError> {
+    Compiler::new(source.into(), &mut Heap::new()).compile()
+  }
 
   #[test]
   fn test_compile() {
     let source = std::fs::read_to_string("./test.lox").unwrap();
-    let mut compiler = Compiler::new(source);
-    compiler.compile().unwrap();
-    compiler.chunk.disassembly("result");
-    assert!(true);
+    let mut heap = Heap::new();
+    let function = Compiler::new(source, &mut heap).compile().unwrap();
+    function.chunk.disassembly("result", &heap);
+  }
+
+  #[test]
+  fn test_missing_semicolon() {
+    assert!(compile("print 1 + 2").is_err());
+    assert!(compile("1 + 2; print 3;").is_ok());
+  }
+
+  #[test]
+  fn test_scanner_error() {
+    // the scanner errors are returned rather than skipping the token
+    let e = compile("print \"a\\q\";").err().unwrap();
+    assert!(e.to_string().contains("invalid escape"), "{}", e);
+  }
+
+  #[test]
+  fn test_assignment_target() {
+    assert!(compile("var a = 1; var b; b = a = 2;").is_ok());
+    assert!(compile("var a; var b; var c; a + b = c;").is_err());
+  }
+
+  #[test]
+  fn test_local_declaration() {
+    assert!(compile("var a = 1; { var b = a; }").is_ok());
+    assert!(compile("{ var a = 1; { var a = a; } }").is_err());
+    assert!(compile("{ var a = 1; var a = 2; }").is_err());
+    assert!(compile("{ var a = 1; { var a = 2; } }").is_ok());
+  }
+
+  #[test]
+  fn test_control_flow() {
+    let source = "var a = 0; if (a > 1 and a < 3 or !a) print a; else a = 1; \
+                  while (a < 10) a = a + 1; for (var i = 0; i < 3; i = i + 1) { print i; } for (;;) {}";
+    assert!(compile(source).is_ok());
+    assert!(compile("if a > 1 print a;").is_err());
+  }
+
+  #[test]
+  fn test_function() {
+    assert!(compile("fun f(a, b) { return a + b; } print f(1, 2);").is_ok());
+    assert!(compile("fun f() { return; } f()();").is_ok());
+    assert!(compile("return 1;").is_err());
+    assert!(compile("fun f(a,) {}").is_err());
+  }
+
+  #[test]
+  fn test_closure() {
+    let source =
+      "fun outer() { var x = 1; fun middle() { fun inner() { x = x + 1; return x; } return inner; } return middle; }";
+    let upvalues = |function: &Function| {
+      function
+        .upvalues
+        .iter()
+        .map(|upvalue| (upvalue.is_local, upvalue.index))
+        .collect::<Vec<_>>()
+    };
+    let mut heap = Heap::new();
+    let script = Compiler::new(source.into(), &mut heap).compile().unwrap();
+    let outer = heap.get(script.chunk.get_constant(1).as_function().unwrap());
+    assert_eq!(upvalues(outer), []);
+    let middle = heap.get(outer.chunk.get_constant(1).as_function().unwrap());
+    assert_eq!(upvalues(middle), [(true, 1)]);
+    let inner = heap.get(middle.chunk.get_constant(0).as_function().unwrap());
+    assert_eq!(upvalues(inner), [(false, 0)]);
+  }
+
+  #[test]
+  fn test_class() {
+    let source = "class A { init(x) { this.x = x; return; } get() { return this.x; } } print A(1).get(); A(2).x = 3;";
+    assert!(compile(source).is_ok());
+    assert!(compile("print this;").is_err());
+    assert!(compile("fun f() { return this; }").is_err());
+    assert!(compile("class A { init() { return 1; } }").is_err());
+  }
+
+  #[test]
+  fn test_inheritance() {
+    let source = "class A { f() { return 1; } } class B < A { f() { return super.f() + 1; } g() { return super.f; } }";
+    assert!(compile(source).is_ok());
+    assert!(compile("class A < A {}").is_err());
+    assert!(compile("class A { f() { return super.f(); } }").is_err());
+    assert!(compile("fun f() { super.f(); }").is_err());
+  }
+
+  #[test]
+  fn test_wide_constant() {
+    // without folding, every literal stays in the pool
+    let compile = |source: String| Compiler::with_opt_level(source, &mut Heap::new(), OptLevel::O0).compile();
+    let sum = (0..300).map(|i| i.to_string()).collect::<Vec<_>>().join(" + ");
+    let function = compile(format!("print {};", sum)).unwrap();
+    let fetch = |i| function.chunk.fetch(i);
+    assert!(matches!(fetch(0), OpCode::Constant(0)));
+    assert!(matches!(fetch(2 * 256 - 1), OpCode::ConstantLong(256)));
+    // identical literals share a slot
+    let repeated = vec!["1"; 300].join(" + ");
+    let function = compile(format!("print {};", repeated)).unwrap();
+    assert_eq!(function.chunk.constants().len(), 1);
+    // so are names
+    let globals = (0..300).map(|i| format!("var v{} = nil;", i)).collect::<String>();
+    let function = compile(globals).unwrap();
+    assert!(matches!(
+      function.chunk.fetch(2 * 256 + 1),
+      OpCode::DefineGlobalLong(256)
+    ));
+  }
+
+  #[test]
+  fn test_constant_folding() {
+    let mut heap = Heap::new();
+    let mut compile = |source: &str, opt_level| {
+      let function = Compiler::with_opt_level(source.into(), &mut heap, opt_level)
+        .compile()
+        .unwrap();
+      (0..function.chunk.len())
+        .map(|i| function.chunk.fetch(i))
+        .collect::<Vec<_>>()
+    };
+    let code = compile("print 1 + (2 + 3) * 4;", OptLevel::O1);
+    assert!(matches!(
+      code[..],
+      [OpCode::Constant(_), OpCode::Print, OpCode::Nil, OpCode::Return]
+    ));
+    let code = compile("print !(1 < 2) == false;", OptLevel::O1);
+    assert!(matches!(code[0], OpCode::True));
+    // the type error is left to the runtime
+    let code = compile("print -\"a\" + 1;", OptLevel::O1);
+    assert!(matches!(
+      code[..3],
+      [OpCode::Constant(_), OpCode::Neg, OpCode::Constant(_)]
+    ));
+    // the jump of `or` lands between the operands
+    let code = compile("var a; print (a or 1) + 2;", OptLevel::O1);
+    assert!(code.iter().any(|op| matches!(op, OpCode::Add)));
+    let code = compile("var a; print a != a; print a <= a; print !(a < a);", OptLevel::O1);
+    assert!(!code.iter().any(|op| matches!(op, OpCode::Not)));
+    assert_eq!(code.iter().filter(|op| matches!(op, OpCode::GreaterEqual)).count(), 1);
+    let code = compile("var a; print 1 + 2; print a != a;", OptLevel::O0);
+    assert!(code.iter().any(|op| matches!(op, OpCode::Add)));
+    assert!(code.iter().any(|op| matches!(op, OpCode::Not)));
+  }
+
+  #[test]
+  fn test_fold_string() {
+    let mut heap = Heap::new();
+    let function = Compiler::new("print \"a\" + \"b\";".into(), &mut heap)
+      .compile()
+      .unwrap();
+    let OpCode::Constant(i) = function.chunk.fetch(0) else {
+      panic!("the concatenation is not folded");
+    };
+    let folded = function.chunk.get_constant(i as usize).as_string().unwrap();
+    assert!(heap.interned("ab") == Some(folded));
+  }
+
+  #[test]
+  fn test_fold_constant_count() {
+    let mut heap = Heap::new();
+    let compile = |source: &str, heap: &mut Heap| Compiler::new(source.into(), heap).compile().unwrap();
+    // the operands of folded expressions are dropped from the pool
+    assert_eq!(compile("print \"a\" + \"b\";", &mut heap).chunk.constants().len(), 1);
+    assert_eq!(compile("print 1 + (2 + 3) * 4;", &mut heap).chunk.constants().len(), 1);
+    // a constant still referred is kept, and the later ones are renumbered
+    let function = compile("var x = 5; print 2 + 3; var y = \"a\" + \"b\"; print x + y;", &mut heap);
+    let constants: Vec<_> = function.chunk.constants().iter().map(|c| c.to_string(&heap)).collect();
+    assert_eq!(constants, ["x", "5", "y", "ab"]);
+    assert_eq!(verify(&function, &heap), Ok(()));
+    // the folded operands beyond 255 no longer need wide instructions
+    let sum = (0..300).map(|i| i.to_string()).collect::<Vec<_>>().join(" + ");
+    let function = compile(&format!("print {}; var v = 1;", sum), &mut heap);
+    assert_eq!(function.chunk.constants().len(), 3);
+    assert!(matches!(function.chunk.fetch(3), OpCode::DefineGlobal(2)));
   }
 }
@@ -1,20 +1,246 @@
+use crate::span::Span;
+
 #[derive(Debug)]
 pub struct CompileError {
-  line: usize,
+  /// The source code the error points at.
+  span: Span,
   msg: String,
   literal: String,
 }
 
 impl CompileError {
-  pub fn new(line: usize, literal: String, msg: String) -> Self {
-    Self { line, literal, msg }
+  pub fn new(span: Span, literal: String, msg: String) -> Self {
+    Self { span, literal, msg }
+  }
+
+  pub fn span(&self) -> &Span {
+    &self.span
   }
 }
 
 impl std::fmt::Display for CompileError {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "[line {}] Error Error at {}: {}", self.line, self.literal, self.msg)
+    write!(
+      f,
+      "[line {}, column {}] Error Error at {}: {}",
+      self.span.line, self.span.column, self.literal, self.msg
+    )
   }
 }
 
 impl std::error::Error for CompileError {}
+
+/// An error found when loading a compiled bytecode file.
+#[derive(Debug)]
+pub enum BytecodeError {
+  /// The file could not be decoded, `offset` is the byte offset in the file where the error is found.
+  Malformed { offset: usize, msg: String },
+  /// The file is decoded but the code is rejected by the verifier.
+  Invalid(Vec<VerifyError>),
+}
+
+impl BytecodeError {
+  pub fn new(offset: usize, msg: String) -> Self {
+    Self::Malformed { offset, msg }
+  }
+}
+
+impl std::fmt::Display for BytecodeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Malformed { offset, msg } => write!(f, "[offset {}] Error in bytecode: {}", offset, msg),
+      Self::Invalid(errors) => {
+        write!(f, "Error in bytecode: {} verification error(s)", errors.len())?;
+        errors.iter().try_for_each(|e| write!(f, "\n  {}", e))
+      }
+    }
+  }
+}
+
+impl std::error::Error for BytecodeError {}
+
+/// An instruction rejected by the bytecode verifier.
+#[derive(Debug, PartialEq, Eq)]
+pub struct VerifyError {
+  /// The function the instruction belongs to, formatted as `<fn name>` or `<script>`.
+  pub function: String,
+  /// The index of the instruction in the chunk.
+  pub index: usize,
+  pub kind: VerifyErrorKind,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+  /// The script takes arguments, it is always called with none.
+  ScriptArity(usize),
+  /// The script captures variables, there is nothing for it to capture.
+  ScriptUpvalues(usize),
+  /// The line table does not cover exactly the code.
+  LineTableMismatch,
+  /// The chunk does not end with `Return`, so the execution could run past the end.
+  MissingReturn,
+  ConstantOutOfRange(usize),
+  /// The constant at `index` is not the kind of value the instruction expects.
+  ConstantType {
+    index: usize,
+    expected: &'static str,
+  },
+  JumpOutOfRange,
+  LocalOutOfRange(u8),
+  UpvalueOutOfRange(u8),
+  StackUnderflow {
+    depth: usize,
+    needed: usize,
+  },
+  StackOverflow(usize),
+  /// The instruction is reached with different stack depths along different paths.
+  InconsistentStackDepth {
+    expected: usize,
+    found: usize,
+  },
+}
+
+impl std::fmt::Display for VerifyError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    use VerifyErrorKind::*;
+    write!(f, "[{} {:04}] ", self.function, self.index)?;
+    match &self.kind {
+      ScriptArity(arity) => write!(f, "script takes {} arguments, expected 0", arity),
+      ScriptUpvalues(count) => write!(f, "script captures {} upvalues, expected 0", count),
+      LineTableMismatch => write!(f, "line table does not match the code"),
+      MissingReturn => write!(f, "chunk does not end with RETURN"),
+      ConstantOutOfRange(i) => write!(f, "constant {} out of range", i),
+      ConstantType { index, expected } => write!(f, "constant {} is not a {}", index, expected),
+      JumpOutOfRange => write!(f, "jump target out of range"),
+      LocalOutOfRange(slot) => write!(f, "local slot {} out of range", slot),
+      UpvalueOutOfRange(i) => write!(f, "upvalue {} out of range", i),
+      StackUnderflow { depth, needed } => write!(f, "stack underflow, needs {} values but has {}", needed, depth),
+      StackOverflow(depth) => write!(f, "stack overflow, depth {}", depth),
+      InconsistentStackDepth { expected, found } => {
+        write!(
+          f,
+          "inconsistent stack depth, {} on one path but {} on another",
+          expected, found
+        )
+      }
+    }
+  }
+}
+
+/// The kind of a runtime error.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+  /// An operand has the wrong type, carrying what the operation expects and the types it got.
+  TypeMismatch {
+    expected: &'static str,
+    found: String,
+  },
+  UndefinedVariable(String),
+  UndefinedProperty(String),
+  StackOverflow,
+  ArityMismatch {
+    expected: usize,
+    got: usize,
+  },
+  /// The type of the callee.
+  NotCallable(&'static str),
+}
+
+impl std::fmt::Display for RuntimeErrorKind {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    use RuntimeErrorKind::*;
+    match self {
+      TypeMismatch { expected, found } => write!(f, "{}, got {}", expected, found),
+      UndefinedVariable(name) => write!(f, "undefined variable '{}'", name),
+      UndefinedProperty(name) => write!(f, "undefined property '{}'", name),
+      StackOverflow => write!(f, "stack overflow"),
+      ArityMismatch { expected, got } => write!(f, "expected {} arguments but got {}", expected, got),
+      NotCallable(typ) => write!(f, "can only call functions and classes, got {}", typ),
+    }
+  }
+}
+
+/// A call frame active when a runtime error is raised.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TraceFrame {
+  pub line: usize,
+  /// `None` for the top-level script.
+  pub function: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct RuntimeError {
+  pub kind: RuntimeErrorKind,
+  /// The source code of the instruction raising the error.
+  pub span: Span,
+  /// The call frames from the innermost one to the script.
+  pub trace: Vec<TraceFrame>,
+}
+
+impl std::fmt::Display for RuntimeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "RuntimeError: [line {}, column {}] {}",
+      self.span.line, self.span.column, self.kind
+    )?;
+    for frame in &self.trace {
+      match &frame.function {
+        Some(name) => write!(f, "\n[line {}] in {}()", frame.line, name)?,
+        None => write!(f, "\n[line {}] in script", frame.line)?,
+      }
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Any error stopping a script from running to the end.
+#[derive(Debug)]
+pub enum InterpretError {
+  Compile(CompileError),
+  Bytecode(BytecodeError),
+  Runtime(RuntimeError),
+}
+
+impl InterpretError {
+  /// The source code the error points at, bytecode errors point at none.
+  pub fn span(&self) -> Option<&Span> {
+    match self {
+      Self::Compile(e) => Some(e.span()),
+      Self::Bytecode(_) => None,
+      Self::Runtime(e) => Some(&e.span),
+    }
+  }
+}
+
+impl From<CompileError> for InterpretError {
+  fn from(e: CompileError) -> Self {
+    Self::Compile(e)
+  }
+}
+
+impl From<BytecodeError> for InterpretError {
+  fn from(e: BytecodeError) -> Self {
+    Self::Bytecode(e)
+  }
+}
+
+impl From<RuntimeError> for InterpretError {
+  fn from(e: RuntimeError) -> Self {
+    Self::Runtime(e)
+  }
+}
+
+impl std::fmt::Display for InterpretError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Compile(e) => write!(f, "{}", e),
+      Self::Bytecode(e) => write!(f, "{}", e),
+      Self::Runtime(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl std::error::Error for InterpretError {}
@@ -18,6 +18,32 @@ macro_rules! def_opcode {
     pub fn for_int_print(&self) -> u32 {
       unsafe{*<*const _>::from(self).cast()}
     }
+
+    /// The name of the variant, without its operands.
+    pub fn name(&self) -> &'static str {
+      use $name::*;
+      match self {
+        $($variant$(($($crate::clean!($carry)),+))? => stringify!($variant)),+
+      }
+    }
+
+    /// Build the opcode whose discriminant is `tag`, reading its operands from `reader`.
+    /// Return `Ok(None)` if no opcode has the discriminant.
+    #[allow(unused_assignments)]
+    pub fn decode(
+      tag: u32,
+      reader: &mut $crate::bytecode::Reader,
+    ) -> Result<Option<Self>, $crate::custom_error::BytecodeError> {
+      use $name::*;
+      let mut discriminant = 0;
+      $(
+        if tag == discriminant {
+          return Ok(Some($variant$(($(reader.read::<$carry>()?),+))?));
+        }
+        discriminant += 1;
+      )+
+      Ok(None)
+    }
   }
 
   impl std::fmt::Display for $name {
@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+mod bytecode;
 mod chunk;
 mod value;
 mod vm;
@@ -6,31 +7,135 @@ mod vm;
 mod def_macro;
 mod compile;
 mod custom_error;
+mod memory;
+mod object;
 mod scanner;
+mod span;
 mod token;
+mod verify;
 
-use crate::compile::Compiler;
+use crate::compile::{Compiler, OptLevel};
+use crate::custom_error::InterpretError;
+use crate::memory::Heap;
+use crate::span::{SourceMap, Span};
 use crate::vm::VM;
 
-fn parse_args() -> Option<String> {
-  let args = std::env::args().collect::<Vec<_>>();
-  if args.len() > 2 {
-    eprintln!("Unexpected argument(s)!. Usage: lox [script]");
-    std::process::exit(1);
+const USAGE: &str = "Usage: lox [-O0|-O1] [--trace-exec] [--print-code] [--stack-limit <n>] [--frame-limit <n>] \
+                     [script]\n       \
+                     lox compile [-O0|-O1] [--print-code] <script> [-o <output>]";
+
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+
+/// The extension of scripts compiled into bytecode, which are run without compiling.
+const BYTECODE_EXTENSION: &str = "loxc";
+
+/// The command line arguments.
+struct Args {
+  command: Command,
+  opt_level: OptLevel,
+  /// Trace every executed instruction to stderr.
+  trace_exec: bool,
+  /// Print the disassembly of the compiled code to stderr.
+  print_code: bool,
+  /// The maximum number of values on the stack, the VM default if `None`.
+  stack_limit: Option<usize>,
+  /// The maximum number of nested calls, the VM default if `None`.
+  frame_limit: Option<usize>,
+}
+
+enum Command {
+  Repl,
+  /// Run a script, or a compiled one if it has the `.loxc` extension.
+  Run(String),
+  /// Compile a script into bytecode, the output defaults to the script path with the `.loxc` extension.
+  Compile {
+    input: String,
+    output: Option<String>,
+  },
+}
+
+fn usage_error(msg: String) -> ! {
+  eprintln!("{}!. {}", msg, USAGE);
+  std::process::exit(1);
+}
+
+/// Parse a positive number following the flag `flag`.
+fn parse_limit(flag: &str, value: Option<String>) -> usize {
+  match value.as_deref().map(str::parse) {
+    Some(Ok(limit)) if limit > 0 => limit,
+    _ => usage_error(format!("'{}' expects a positive number", flag)),
   }
-  assert!(!args.is_empty());
-  if args.len() == 1 {
-    None
-  } else {
-    Some(args[1].clone())
+}
+
+/// Parse the command line arguments `args`, without the program name.
+fn parse_args(args: impl Iterator<Item = String>) -> Args {
+  let mut args = args.peekable();
+  let compile = args.next_if(|arg| arg == "compile").is_some();
+  let mut opt_level = OptLevel::default();
+  let mut trace_exec = false;
+  let mut print_code = false;
+  let mut stack_limit = None;
+  let mut frame_limit = None;
+  let mut path = None;
+  let mut output = None;
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "-O0" => opt_level = OptLevel::O0,
+      "-O1" => opt_level = OptLevel::O1,
+      "--trace-exec" | "--print-code" if !cfg!(feature = "trace") => usage_error(format!(
+        "'{}' needs the interpreter built with the `trace` feature",
+        arg
+      )),
+      "--trace-exec" if !compile => trace_exec = true,
+      "--print-code" => print_code = true,
+      "--stack-limit" if !compile => stack_limit = Some(parse_limit(&arg, args.next())),
+      "--frame-limit" if !compile => frame_limit = Some(parse_limit(&arg, args.next())),
+      "-o" if compile && output.is_none() => match args.next() {
+        Some(arg) => output = Some(arg),
+        None => usage_error("Missing output path".into()),
+      },
+      _ if arg.starts_with('-') || path.is_some() => usage_error(format!("Unexpected argument '{}'", arg)),
+      _ => path = Some(arg),
+    }
+  }
+  let command = match (compile, path) {
+    (true, Some(input)) => Command::Compile { input, output },
+    (true, None) => usage_error("Missing script to compile".into()),
+    (false, Some(path)) => Command::Run(path),
+    (false, None) => Command::Repl,
+  };
+  Args {
+    command,
+    opt_level,
+    trace_exec,
+    print_code,
+    stack_limit,
+    frame_limit,
   }
 }
 
-fn repl() {
+/// A VM configured by the command line arguments.
+fn new_vm(args: &Args) -> VM {
+  let mut vm = VM::new();
+  vm.set_opt_level(args.opt_level);
+  vm.set_trace_exec(args.trace_exec);
+  vm.set_print_code(args.print_code);
+  if let Some(limit) = args.stack_limit {
+    vm.set_stack_limit(limit);
+  }
+  if let Some(limit) = args.frame_limit {
+    vm.set_frame_limit(limit);
+  }
+  vm
+}
+
+fn repl(args: &Args) {
   use std::io::BufRead;
   use std::io::Write;
   let mut reader = std::io::BufReader::new(std::io::stdin());
   let mut buf = String::new();
+  let mut vm = new_vm(args);
   loop {
     print!("> ");
     std::io::stdout().flush().unwrap();
@@ -38,38 +143,108 @@ fn repl() {
     if buf.is_empty() {
       continue;
     }
-    let mut compiler = Compiler::new(buf.clone());
-    if let Err(e) = compiler.compile() {
-      eprintln!("{}", e);
-      continue;
+    if let Err(e) = vm.interpret_file("<repl>".into(), buf.clone()) {
+      report(&e, e.span(), vm.source_map());
     }
-    let mut vm = VM::new(compiler.return_chunk());
-    vm.run();
     buf.clear();
   }
 }
 
-fn run_source(path: String) {
-  let source = std::fs::read_to_string(path);
-  if let Err(e) = source {
-    eprintln!("Error during read file: {}", e);
-    std::process::exit(1);
+fn run_source(path: &str, args: &Args) {
+  let mut vm = new_vm(args);
+  let result = if std::path::Path::new(path).extension() == Some(BYTECODE_EXTENSION.as_ref()) {
+    let bytes = read_file(std::fs::read(path));
+    vm.interpret_bytecode(path.into(), &bytes)
   } else {
-    let mut compiler = Compiler::new(source.unwrap());
-    if let Err(e) = compiler.compile() {
-      eprintln!("{}", e);
-      std::process::exit(1);
+    let source = read_file(std::fs::read_to_string(path));
+    vm.interpret_file(path.into(), source)
+  };
+  if let Err(e) = result {
+    report(&e, e.span(), vm.source_map());
+    std::process::exit(exit_code(&e));
+  }
+}
+
+/// Print the error `e`, followed by the source code it points at.
+fn report(e: &dyn std::fmt::Display, span: Option<&Span>, source_map: &SourceMap) {
+  eprintln!("{}", e);
+  if let Some(span) = span {
+    eprint!("{}", source_map.snippet(span));
+  }
+}
+
+/// The exit code of a failed script, following sysexits: 65 for bad input and 70 for runtime errors.
+fn exit_code(e: &InterpretError) -> i32 {
+  match e {
+    InterpretError::Compile(_) | InterpretError::Bytecode(_) => EX_DATAERR,
+    InterpretError::Runtime(_) => EX_SOFTWARE,
+  }
+}
+
+fn compile_source(input: &str, output: Option<&str>, args: &Args) {
+  let source = read_file(std::fs::read_to_string(input));
+  let mut source_map = SourceMap::new();
+  let file = source_map.add(input.into(), source.clone());
+  let mut heap = Heap::new();
+  let mut compiler = Compiler::with_opt_level(source, &mut heap, args.opt_level);
+  compiler.set_file(file);
+  compiler.set_print_code(args.print_code);
+  let function = match compiler.compile() {
+    Ok(function) => function,
+    Err(e) => {
+      report(&e, Some(e.span()), &source_map);
+      std::process::exit(EX_DATAERR);
     }
-    let mut vm = VM::new(compiler.return_chunk());
-    vm.run();
+  };
+  let output = output.map_or_else(
+    || std::path::Path::new(input).with_extension(BYTECODE_EXTENSION),
+    std::path::PathBuf::from,
+  );
+  if let Err(e) = std::fs::write(output, bytecode::write(&function, &heap)) {
+    eprintln!("Error during write file: {}", e);
+    std::process::exit(1);
   }
 }
 
+fn read_file<T>(content: std::io::Result<T>) -> T {
+  content.unwrap_or_else(|e| {
+    eprintln!("Error during read file: {}", e);
+    std::process::exit(1);
+  })
+}
+
 fn main() {
-  let path = parse_args();
-  if let Some(path) = path {
-    run_source(path);
-  } else {
-    repl();
+  let args = parse_args(std::env::args().skip(1));
+  match &args.command {
+    Command::Repl => repl(&args),
+    Command::Run(path) => run_source(path, &args),
+    Command::Compile { input, output } => compile_source(input, output.as_deref(), &args),
+  }
+}
+
+#[cfg(test)]
+mod main_test {
+  use super::*;
+
+  #[test]
+  fn test_scanner_error_exit_code() {
+    let e = VM::new().interpret("print 1;\n/* unterminated\n".into()).err().unwrap();
+    assert!(matches!(e, InterpretError::Compile(_)));
+    assert_eq!(
+      e.to_string(),
+      "[line 2, column 1] Error Error at /*: unterminated block comment"
+    );
+    assert_eq!(exit_code(&e), 65);
+  }
+
+  #[test]
+  fn test_limit_args() {
+    let args = ["--stack-limit", "512", "--frame-limit", "8", "test.lox"].map(String::from);
+    let args = parse_args(args.into_iter());
+    assert!(matches!(args.command, Command::Run(ref path) if path == "test.lox"));
+    assert_eq!((args.stack_limit, args.frame_limit), (Some(512), Some(8)));
+    let args = parse_args(std::iter::empty());
+    assert!(matches!(args.command, Command::Repl));
+    assert_eq!((args.stack_limit, args.frame_limit), (None, None));
   }
 }
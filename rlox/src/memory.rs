@@ -0,0 +1,416 @@
+use crate::object::{BoundMethod, Class, Closure, Function, Instance, Upvalue};
+use crate::value::Value;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/// A handle to an object of type `T` managed by `Heap`.
+/// The handle is only an index, the object must be accessed via `Heap::get` and `Heap::get_mut`.
+pub struct Gc<T> {
+  index: usize,
+  _marker: PhantomData<T>,
+}
+
+impl<T> Gc<T> {
+  fn new(index: usize) -> Self {
+    Self {
+      index,
+      _marker: PhantomData,
+    }
+  }
+}
+
+impl<T> Clone for Gc<T> {
+  fn clone(&self) -> Self {
+    *self
+  }
+}
+
+impl<T> Copy for Gc<T> {}
+
+impl<T> PartialEq for Gc<T> {
+  fn eq(&self, other: &Self) -> bool {
+    self.index == other.index
+  }
+}
+
+impl<T> Eq for Gc<T> {}
+
+impl<T> std::hash::Hash for Gc<T> {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.index.hash(state);
+  }
+}
+
+/// Every kind of object living on the heap.
+pub enum Object {
+  Str(String),
+  Function(Function),
+  Closure(Closure),
+  Upvalue(Upvalue),
+  Class(Class),
+  Instance(Instance),
+  BoundMethod(BoundMethod),
+}
+
+/// Conversion between a concrete object type and `Object`.
+pub trait HeapObject: Sized {
+  fn into_object(self) -> Object;
+  fn from_object(object: &Object) -> &Self;
+  fn from_object_mut(object: &mut Object) -> &mut Self;
+}
+
+macro_rules! heap_object {
+  ($typ:ty, $variant:ident) => {
+    impl HeapObject for $typ {
+      fn into_object(self) -> Object {
+        Object::$variant(self)
+      }
+
+      fn from_object(object: &Object) -> &Self {
+        match object {
+          Object::$variant(o) => o,
+          _ => panic!("Fatal: heap object is not a {}", stringify!($variant)),
+        }
+      }
+
+      fn from_object_mut(object: &mut Object) -> &mut Self {
+        match object {
+          Object::$variant(o) => o,
+          _ => panic!("Fatal: heap object is not a {}", stringify!($variant)),
+        }
+      }
+    }
+  };
+}
+
+heap_object!(String, Str);
+heap_object!(Function, Function);
+heap_object!(Closure, Closure);
+heap_object!(Upvalue, Upvalue);
+heap_object!(Class, Class);
+heap_object!(Instance, Instance);
+heap_object!(BoundMethod, BoundMethod);
+
+impl Object {
+  /// The approximate number of bytes owned by the object, used to decide when to collect.
+  fn size(&self) -> usize {
+    use std::mem::size_of;
+    let owned = match self {
+      // every string is interned, and the intern table keeps a copy of the text as its key
+      Object::Str(s) => s.capacity() + s.len() + size_of::<(String, Gc<String>)>(),
+      Object::Function(function) => function.chunk.len() * size_of::<crate::chunk::OpCode>(),
+      Object::Closure(closure) => closure.upvalues.capacity() * size_of::<Gc<Upvalue>>(),
+      Object::Class(class) => class.methods.capacity() * size_of::<(Gc<String>, Gc<Closure>)>(),
+      Object::Instance(instance) => instance.fields.capacity() * size_of::<(Gc<String>, Value)>(),
+      Object::Upvalue(_) | Object::BoundMethod(_) => 0,
+    };
+    size_of::<Object>() + owned
+  }
+}
+
+/// Tunables of the garbage collector.
+#[derive(Clone, Copy)]
+pub struct GcConfig {
+  /// The number of allocated bytes that triggers the first collection.
+  pub initial_threshold: usize,
+  /// After a collection, the next one is triggered when the allocated bytes grows to `live bytes * grow_factor`.
+  pub grow_factor: usize,
+  /// Collect on every allocation, which is used to shake out GC bugs.
+  pub stress: bool,
+}
+
+impl Default for GcConfig {
+  fn default() -> Self {
+    Self {
+      initial_threshold: 1024 * 1024,
+      grow_factor: 2,
+      stress: false,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct GcStats {
+  pub collections: usize,
+  /// Total bytes ever allocated.
+  pub bytes_allocated: usize,
+  /// Total bytes ever freed.
+  pub bytes_freed: usize,
+}
+
+struct Entry {
+  marked: bool,
+  size: usize,
+  object: Object,
+}
+
+/// The heap of GC-managed objects, collected by mark-and-sweep.
+/// The heap does not know the roots, so it never collects by itself. The owner checks `should_collect`
+/// before allocation, marks the roots with `mark_value`/`mark`, then calls `collect`.
+pub struct Heap {
+  entries: Vec<Option<Entry>>,
+  /// Indices of the freed entries, reused by later allocations.
+  free: Vec<usize>,
+  /// Indices of the marked objects whose references are not traced yet.
+  gray: Vec<usize>,
+  /// The interned strings. Every string on the heap is interned, so two strings are equal iff their handles are.  
+  /// The table does not keep the strings alive, unreachable ones are removed before sweeping.
+  strings: HashMap<String, Gc<String>>,
+  /// Bytes of live objects.
+  bytes_allocated: usize,
+  next_gc: usize,
+  config: GcConfig,
+  stats: GcStats,
+}
+
+impl Heap {
+  pub fn new() -> Self {
+    Self::with_config(GcConfig::default())
+  }
+
+  pub fn with_config(config: GcConfig) -> Self {
+    Self {
+      entries: Vec::new(),
+      free: Vec::new(),
+      gray: Vec::new(),
+      strings: HashMap::new(),
+      bytes_allocated: 0,
+      next_gc: config.initial_threshold,
+      config,
+      stats: GcStats::default(),
+    }
+  }
+
+  pub fn stats(&self) -> GcStats {
+    self.stats
+  }
+
+  /// The number of live objects.
+  pub fn object_count(&self) -> usize {
+    self.entries.len() - self.free.len()
+  }
+
+  /// Move `object` into the heap and return its handle.  
+  /// Strings must be allocated by `intern` instead.
+  pub fn alloc<T: HeapObject>(&mut self, object: T) -> Gc<T> {
+    let object = object.into_object();
+    debug_assert!(!matches!(object, Object::Str(_)), "strings must be interned");
+    Gc::new(self.alloc_object(object))
+  }
+
+  /// Return the handle of the interned string equal to `s`, if there is one.
+  pub fn interned(&self, s: &str) -> Option<Gc<String>> {
+    self.strings.get(s).copied()
+  }
+
+  /// Return the handle of the interned string equal to `s`, allocate one if `s` is not interned yet.
+  pub fn intern(&mut self, s: String) -> Gc<String> {
+    if let Some(handle) = self.interned(&s) {
+      return handle;
+    }
+    let handle = Gc::new(self.alloc_object(Object::Str(s.clone())));
+    self.strings.insert(s, handle);
+    handle
+  }
+
+  fn alloc_object(&mut self, object: Object) -> usize {
+    let size = object.size();
+    self.bytes_allocated += size;
+    self.stats.bytes_allocated += size;
+    let entry = Some(Entry {
+      marked: false,
+      size,
+      object,
+    });
+    match self.free.pop() {
+      Some(index) => {
+        self.entries[index] = entry;
+        index
+      }
+      None => {
+        self.entries.push(entry);
+        self.entries.len() - 1
+      }
+    }
+  }
+
+  /// Retrieve the object `handle` refers to.
+  /// Panics if the object has been freed, which means a root is missing.
+  pub fn get<T: HeapObject>(&self, handle: Gc<T>) -> &T {
+    T::from_object(&self.entry(handle.index).object)
+  }
+
+  pub fn get_mut<T: HeapObject>(&mut self, handle: Gc<T>) -> &mut T {
+    let entry = self.entries[handle.index]
+      .as_mut()
+      .expect("Fatal: access to a freed object");
+    T::from_object_mut(&mut entry.object)
+  }
+
+  fn entry(&self, index: usize) -> &Entry {
+    self.entries[index].as_ref().expect("Fatal: access to a freed object")
+  }
+
+  /// Whether the next allocation should be preceded by a collection.
+  pub fn should_collect(&self) -> bool {
+    self.config.stress || self.bytes_allocated > self.next_gc
+  }
+
+  pub fn mark<T>(&mut self, handle: Gc<T>) {
+    self.mark_index(handle.index);
+  }
+
+  pub fn mark_value(&mut self, value: &Value) {
+    if let Some(index) = Self::value_index(value) {
+      self.mark_index(index);
+    }
+  }
+
+  fn mark_index(&mut self, index: usize) {
+    let entry = self.entries[index].as_mut().expect("Fatal: mark a freed object");
+    if entry.marked {
+      return;
+    }
+    entry.marked = true;
+    self.gray.push(index);
+  }
+
+  fn value_index(value: &Value) -> Option<usize> {
+    match value {
+      Value::Str(s) => Some(s.index),
+      Value::Function(function) => Some(function.index),
+      Value::Closure(closure) => Some(closure.index),
+      Value::Class(class) => Some(class.index),
+      Value::Instance(instance) => Some(instance.index),
+      Value::BoundMethod(bound) => Some(bound.index),
+      Value::Number(_) | Value::Boolean(_) | Value::Nil => None,
+    }
+  }
+
+  /// Free every object that is not reachable from the marked roots.
+  pub fn collect(&mut self) {
+    self.trace_references();
+    let entries = &self.entries;
+    self
+      .strings
+      .retain(|_, handle| entries[handle.index].as_ref().is_some_and(|entry| entry.marked));
+    self.sweep();
+    self.next_gc = (self.bytes_allocated * self.config.grow_factor).max(self.config.initial_threshold);
+    self.stats.collections += 1;
+  }
+
+  fn trace_references(&mut self) {
+    let mut children = Vec::new();
+    while let Some(index) = self.gray.pop() {
+      match &self.entry(index).object {
+        Object::Str(_) => {}
+        Object::Function(function) => {
+          children.extend(function.chunk.constants().iter().filter_map(Self::value_index));
+        }
+        Object::Closure(closure) => {
+          children.push(closure.function.index);
+          children.extend(closure.upvalues.iter().map(|upvalue| upvalue.index));
+        }
+        Object::Upvalue(Upvalue::Closed(value)) => children.extend(Self::value_index(value)),
+        Object::Upvalue(Upvalue::Open(_)) => {}
+        Object::Class(class) => {
+          for (name, method) in &class.methods {
+            children.push(name.index);
+            children.push(method.index);
+          }
+        }
+        Object::Instance(instance) => {
+          children.push(instance.class.index);
+          for (name, field) in &instance.fields {
+            children.push(name.index);
+            children.extend(Self::value_index(field));
+          }
+        }
+        Object::BoundMethod(bound) => {
+          children.extend(Self::value_index(&bound.receiver));
+          children.push(bound.method.index);
+        }
+      }
+      for child in children.drain(..) {
+        self.mark_index(child);
+      }
+    }
+  }
+
+  fn sweep(&mut self) {
+    for (index, slot) in self.entries.iter_mut().enumerate() {
+      match slot {
+        Some(entry) if entry.marked => entry.marked = false,
+        Some(entry) => {
+          self.bytes_allocated -= entry.size;
+          self.stats.bytes_freed += entry.size;
+          *slot = None;
+          self.free.push(index);
+        }
+        None => {}
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod memory_test {
+  use super::*;
+  use crate::object::Instance;
+
+  #[test]
+  fn test_collect() {
+    let mut heap = Heap::new();
+    let class = heap.alloc(Class::new("A".into()));
+    let instance = heap.alloc(Instance::new(class));
+    let key = heap.intern("f".into());
+    let name = heap.intern("field".into());
+    heap.get_mut(instance).fields.insert(key, Value::Str(name));
+    let garbage = heap.intern("garbage".into());
+    assert_eq!(heap.object_count(), 5);
+
+    // the class and the strings are reachable from the instance
+    heap.mark(instance);
+    heap.collect();
+    assert_eq!(heap.object_count(), 4);
+    assert_eq!(heap.get(name), "field");
+    assert!(heap.stats().bytes_freed > 0);
+
+    // the freed slot is reused
+    let reused = heap.intern("reused".into());
+    assert!(reused == garbage);
+    heap.collect();
+    assert_eq!(heap.object_count(), 0);
+  }
+
+  #[test]
+  fn test_intern() {
+    let mut heap = Heap::new();
+    let a = heap.intern("lox".into());
+    assert!(a == heap.intern(String::from("lo") + "x"));
+    assert!(heap.interned("lox") == Some(a));
+    // an unreachable string is removed from the intern table as well
+    heap.collect();
+    assert!(heap.interned("lox").is_none());
+  }
+
+  #[test]
+  fn test_size() {
+    use std::mem::size_of;
+    let text = "x".repeat(1000);
+    let s = Object::Str(text.clone());
+    // the text is stored both in the object and as the key of the intern table
+    assert!(s.size() >= 2 * text.len());
+
+    let mut heap = Heap::new();
+    let class = heap.alloc(Class::new("A".into()));
+    let mut instance = Instance::new(class);
+    instance.fields.insert(heap.intern("f".into()), Value::Nil);
+    let capacity = instance.fields.capacity();
+    let instance = Object::Instance(instance);
+    assert_eq!(
+      instance.size(),
+      size_of::<Object>() + capacity * size_of::<(Gc<String>, Value)>()
+    );
+  }
+}
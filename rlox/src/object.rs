@@ -0,0 +1,102 @@
+use crate::chunk::Chunk;
+use crate::memory::Gc;
+use crate::value::Value;
+use std::collections::HashMap;
+
+/// A compiled Lox function, which owns the bytecode of its body.
+pub struct Function {
+  pub arity: usize,
+  pub chunk: Chunk,
+  /// `None` for the top-level script.
+  pub name: Option<String>,
+  /// Descriptions of the variables captured by the function.
+  pub upvalues: Vec<UpvalueDesc>,
+}
+
+impl Function {
+  pub fn new(name: Option<String>) -> Self {
+    Self {
+      arity: 0,
+      chunk: Chunk::new(),
+      name,
+      upvalues: Vec::new(),
+    }
+  }
+}
+
+impl std::fmt::Display for Function {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.name {
+      Some(name) => write!(f, "<fn {}>", name),
+      None => write!(f, "<script>"),
+    }
+  }
+}
+
+/// How a closure captures an upvalue when it is created.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueDesc {
+  /// `true` if the upvalue captures a local of the enclosing function,
+  /// otherwise it captures an upvalue of the enclosing function.
+  pub is_local: bool,
+  /// The stack slot of the local, or the index of the upvalue in the enclosing closure.
+  pub index: u8,
+}
+
+/// A variable captured by closures.
+pub enum Upvalue {
+  /// The variable still lives on the VM stack, carrying its absolute stack index.
+  Open(usize),
+  /// The variable has been moved off the stack into the upvalue itself.
+  Closed(Value),
+}
+
+/// A function together with the upvalues it captures at runtime.
+pub struct Closure {
+  pub function: Gc<Function>,
+  pub upvalues: Vec<Gc<Upvalue>>,
+}
+
+impl Closure {
+  pub fn new(function: Gc<Function>, upvalue_count: usize) -> Self {
+    Self {
+      function,
+      upvalues: Vec::with_capacity(upvalue_count),
+    }
+  }
+}
+
+pub struct Class {
+  pub name: String,
+  /// Methods defined in the class body.
+  pub methods: HashMap<Gc<String>, Gc<Closure>>,
+}
+
+impl Class {
+  pub fn new(name: String) -> Self {
+    Self {
+      name,
+      methods: HashMap::new(),
+    }
+  }
+}
+
+pub struct Instance {
+  pub class: Gc<Class>,
+  pub fields: HashMap<Gc<String>, Value>,
+}
+
+impl Instance {
+  pub fn new(class: Gc<Class>) -> Self {
+    Self {
+      class,
+      fields: HashMap::new(),
+    }
+  }
+}
+
+/// A method accessed from an instance, which remembers the instance as `this`.
+pub struct BoundMethod {
+  pub receiver: Value,
+  pub method: Gc<Closure>,
+}
@@ -1,80 +1,153 @@
 use crate::custom_error::CompileError;
+use crate::span::{FileId, Span};
 use crate::token::*;
 
+/// Scan tokens from UTF-8 source, all positions are byte offsets.
 pub struct Scanner {
   start: usize,
   current: usize,
   line: usize,
-  source: Vec<char>,
+  /// The offset of the first byte of the current line.
+  line_start: usize,
+  /// An offset on the current line and its column, columns are counted in characters from it
+  /// so a long line is not counted again for each token.
+  known_column: (usize, usize),
+  /// The position where the token being scanned starts, a token may span lines.
+  start_line: usize,
+  start_column: usize,
+  /// The file the spans of the tokens point into.
+  file: FileId,
+  source: String,
 }
 
 type ScanResult = Result<Token, CompileError>;
 
 // Constructor
 impl Scanner {
-  /// Construct a scanner from a `String`, then append a `'\0'`.
   pub fn new(source: String) -> Self {
-    let mut source: Vec<char> = source.chars().collect();
-    source.push('\0');
     Self {
       start: 0,
       current: 0,
       line: 1,
+      line_start: 0,
+      known_column: (0, 1),
+      start_line: 1,
+      start_column: 1,
+      file: FileId::default(),
       source,
     }
   }
 
+  pub fn set_file(&mut self, file: FileId) {
+    self.file = file;
+  }
+
   /// Return a unmutable slice of source
-  pub fn source(&self) -> &[char] {
+  pub fn source(&self) -> &str {
     &self.source
   }
 }
 
 // help functions
 impl Scanner {
-  fn get(&self, index: usize) -> char {
-    unsafe { *self.source.get_unchecked(index) }
+  /// The byte at `index`, or `0` past the end. An embedded NUL is told apart from the end by `is_at_end`.
+  fn get(&self, index: usize) -> u8 {
+    self.source.as_bytes().get(index).copied().unwrap_or(0)
   }
 
-  fn advance(&mut self) -> char {
+  fn advance(&mut self) -> u8 {
     self.current += 1;
     self.get(self.current - 1)
   }
 
-  fn peek(&self) -> char {
+  fn peek(&self) -> u8 {
     self.get(self.current)
   }
 
-  fn skip_whitespace(&mut self) {
+  /// The character starting at `current`, which may take several bytes.
+  fn peek_char(&self) -> Option<char> {
+    self.source[self.current..].chars().next()
+  }
+
+  /// Skip whitespace and comments, `/* */` comments may nest.
+  fn skip_whitespace(&mut self) -> Result<(), CompileError> {
     loop {
       match self.peek() {
-        c if !c.is_ascii_whitespace() => return,
-        '\n' => self.line += 1,
-        '/' => {
-          if self.is_match('/') {
-            while self.peek() != '\n' && !self.is_at_end() {
-              self.advance();
-            }
-          } else {
-            return;
+        b'\n' => self.newline(),
+        c if c.is_ascii_whitespace() => {}
+        b'/' if self.peek_next() == Some(b'/') => {
+          while self.peek() != b'\n' && !self.is_at_end() {
+            self.advance();
           }
+          continue;
+        }
+        b'/' if self.peek_next() == Some(b'*') => {
+          self.skip_block_comment()?;
+          continue;
+        }
+        _ => return Ok(()),
+      }
+      self.advance();
+    }
+  }
+
+  /// Skip a block comment and the ones nested in it, `peek` is the opening `/`.
+  fn skip_block_comment(&mut self) -> Result<(), CompileError> {
+    let (start, line, column) = (self.current, self.line, self.column_at(self.current));
+    let mut depth = 0;
+    loop {
+      match (self.peek(), self.peek_next()) {
+        (b'/', Some(b'*')) => {
+          depth += 1;
+          self.advance();
+        }
+        (b'*', Some(b'/')) => {
+          depth -= 1;
+          self.advance();
+        }
+        _ if self.is_at_end() => {
+          let span = self.span(start, start + 2, line, column);
+          return Err(CompileError::new(
+            span,
+            "/*".into(),
+            "unterminated block comment".into(),
+          ));
         }
+        (b'\n', _) => self.newline(),
         _ => {}
       }
       self.advance();
+      if depth == 0 {
+        return Ok(());
+      }
     }
   }
 
-  fn peek_next(&self) -> Option<char> {
+  /// Move to the next line, called when `peek` is the newline character.
+  fn newline(&mut self) {
+    self.line += 1;
+    self.line_start = self.current + 1;
+    self.known_column = (self.line_start, 1);
+  }
+
+  /// The column of the byte at `index` on the current line, `index` must not be before the last one asked.
+  fn column_at(&mut self, index: usize) -> usize {
+    let (offset, column) = self.known_column;
+    let column = column + self.source[offset..index].chars().count();
+    self.known_column = (index, column);
+    column
+  }
+
+  fn peek_next(&self) -> Option<u8> {
     if self.is_at_end() {
       None
     } else {
-      Some(self.get(self.current + 1))
+      self.source.as_bytes().get(self.current + 1).copied()
     }
   }
 
   /// Check whether the next character is the given `expected`, if true, consume that character.
-  fn is_match(&mut self, expected: char) -> bool {
+  fn is_match(&mut self, expected: u8) -> bool {
     if self.is_at_end() {
       return false;
     }
@@ -86,43 +159,96 @@ impl Scanner {
   }
 
   fn is_at_end(&self) -> bool {
-    self.peek() == '\0'
+    self.current >= self.source.len()
   }
 
-  fn is_legal_ident(c: char) -> bool {
-    c.is_ascii_alphabetic() || c == '_'
+  /// Whether `c` may start an identifier, following the Unicode XID rules with `_` allowed as well.
+  fn is_ident_start(c: char) -> bool {
+    c == '_' || unicode_ident::is_xid_start(c)
   }
 
+  /// Scan a number literal. Letters and digits following it are scanned into the literal,
+  /// so `0xFG` or `12ab` is reported as a malformed literal instead of a number and an identifier.
   fn scan_number(&mut self) -> ScanResult {
-    while self.peek().is_ascii_digit() {
+    self.skip_alphanumeric();
+    if self.peek() == b'.' && self.peek_next().is_some_and(|c| c.is_ascii_digit()) {
       self.advance();
+      self.skip_alphanumeric();
     }
-    if self.is_match('.') && self.peek().is_ascii_digit() {
-      while self.peek().is_ascii_digit() {
-        self.advance();
-      }
+    // the sign of the exponent in a decimal literal like `2.5E-3`
+    let prefixed =
+      self.get(self.start) == b'0' && matches!(self.get(self.start + 1), b'x' | b'X' | b'b' | b'B' | b'o' | b'O');
+    if !prefixed
+      && matches!(self.get(self.current - 1), b'e' | b'E')
+      && matches!(self.peek(), b'+' | b'-')
+      && self.peek_next().is_some_and(|c| c.is_ascii_digit())
+    {
+      self.advance();
+      self.skip_alphanumeric();
+    }
+    let literal = &self.source[self.start..self.current];
+    if let Err(msg) = parse_number(literal) {
+      return Err(CompileError::new(self.token_span(), literal.into(), msg.into()));
     }
     self.make_token(TokenType::Num)
   }
 
-  fn scan_string(&mut self) -> ScanResult {
-    while self.peek() != '"' && !self.is_at_end() {
+  fn skip_alphanumeric(&mut self) {
+    while self.peek().is_ascii_alphanumeric() || self.peek() == b'_' {
       self.advance();
     }
-    if self.is_match('"') {
-      self.make_token(TokenType::Str)
-    } else {
-      Err(CompileError::new(
-        self.line,
-        self.get(self.current - 1).into(),
-        "unterminated string".into(),
-      ))
+  }
+
+  fn scan_string(&mut self) -> ScanResult {
+    while self.peek() != b'"' && !self.is_at_end() {
+      // the escaped character is skipped, it may be a quote
+      if self.peek() == b'\\' {
+        self.advance();
+      }
+      if self.peek() == b'\n' {
+        self.newline();
+      }
+      if !self.is_at_end() {
+        self.advance();
+      }
+    }
+    if !self.is_match(b'"') {
+      let span = self.span(self.start, self.start + 1, self.start_line, self.start_column);
+      return Err(CompileError::new(span, "\"".into(), "unterminated string".into()));
+    }
+    if let Err((range, msg)) = unescape(&self.source[self.start + 1..self.current - 1]) {
+      let (start, end) = (self.start + 1 + range.start, self.start + 1 + range.end);
+      let (line, column) = self.position_of(start);
+      let literal = self.source[start..end].into();
+      return Err(CompileError::new(
+        self.span(start, end, line, column),
+        literal,
+        msg.into(),
+      ));
+    }
+    self.make_token(TokenType::Str)
+  }
+
+  /// The line and column of the byte at `index` in the token being scanned.
+  fn position_of(&self, index: usize) -> (usize, usize) {
+    let scanned = &self.source[self.start..index];
+    let lines = scanned.matches('\n').count();
+    match scanned.rfind('\n') {
+      Some(newline) => (self.start_line + lines, scanned[newline + 1..].chars().count() + 1),
+      None => (self.start_line, self.start_column + scanned.chars().count()),
     }
   }
 
   fn scan_ident(&mut self) -> ScanResult {
-    while Self::is_legal_ident(self.peek()) || self.peek().is_ascii_digit() {
-      self.advance();
+    loop {
+      match self.peek() {
+        c if c.is_ascii_alphanumeric() || c == b'_' => self.current += 1,
+        c if c.is_ascii() => break,
+        _ => match self.peek_char() {
+          Some(c) if unicode_ident::is_xid_continue(c) => self.current += c.len_utf8(),
+          _ => break,
+        },
+      }
     }
     let typ = self.scan_ident_type();
     self.make_token(typ)
@@ -131,26 +257,26 @@ impl Scanner {
   fn scan_ident_type(&mut self) -> TokenType {
     use TokenType::*;
     match self.get(self.start) {
-      'a' => self.check_keyword(1, 2, "nd", And),
-      'c' => self.check_keyword(1, 4, "lass", Class),
-      'e' => self.check_keyword(1, 3, "lse", Else),
-      'i' => self.check_keyword(1, 1, "f", If),
-      'n' => self.check_keyword(1, 2, "il", Nil),
-      'o' => self.check_keyword(1, 1, "r", Or),
-      'p' => self.check_keyword(1, 4, "rint", Print),
-      'r' => self.check_keyword(1, 5, "eturn", Ret),
-      's' => self.check_keyword(1, 4, "uper", Super),
-      'v' => self.check_keyword(1, 2, "ar", Var),
-      'w' => self.check_keyword(1, 4, "hlie", While),
-      'f' if self.current - self.start > 1 => match self.get(self.start + 1) {
-        'a' => self.check_keyword(2, 3, "lse", False),
-        'o' => self.check_keyword(2, 1, "r", For),
-        'u' => self.check_keyword(2, 1, "n", Fun),
+      b'a' => self.check_keyword(1, 2, "nd", And),
+      b'c' => self.check_keyword(1, 4, "lass", Class),
+      b'e' => self.check_keyword(1, 3, "lse", Else),
+      b'i' => self.check_keyword(1, 1, "f", If),
+      b'n' => self.check_keyword(1, 2, "il", Nil),
+      b'o' => self.check_keyword(1, 1, "r", Or),
+      b'p' => self.check_keyword(1, 4, "rint", Print),
+      b'r' => self.check_keyword(1, 5, "eturn", Ret),
+      b's' => self.check_keyword(1, 4, "uper", Super),
+      b'v' => self.check_keyword(1, 2, "ar", Var),
+      b'w' => self.check_keyword(1, 4, "hile", While),
+      b'f' if self.current - self.start > 1 => match self.get(self.start + 1) {
+        b'a' => self.check_keyword(2, 3, "lse", False),
+        b'o' => self.check_keyword(2, 1, "r", For),
+        b'u' => self.check_keyword(2, 1, "n", Fun),
         _ => Ident,
       },
-      't' if self.current - self.start > 1 => match self.get(self.start + 1) {
-        'h' => self.check_keyword(2, 2, "is", This),
-        'r' => self.check_keyword(2, 2, "ue", True),
+      b't' if self.current - self.start > 1 => match self.get(self.start + 1) {
+        b'h' => self.check_keyword(2, 2, "is", This),
+        b'r' => self.check_keyword(2, 2, "ue", True),
         _ => Ident,
       },
       _ => Ident,
@@ -159,16 +285,8 @@ impl Scanner {
 
   fn check_keyword(&self, start: usize, len: usize, rest: &str, typ: TokenType) -> TokenType {
     let cmp_start = self.start + start;
-    let cmp_end = cmp_start + len;
     if self.current - self.start == start + len
-      && unsafe {
-        self
-          .source
-          .get_unchecked(cmp_start..cmp_end)
-          .iter()
-          .zip(rest.chars())
-          .all(|(c1, c2)| *c1 == c2)
-      }
+      && &self.source.as_bytes()[cmp_start..cmp_start + len] == rest.as_bytes()
     {
       typ
     } else {
@@ -176,17 +294,30 @@ impl Scanner {
     }
   }
 
+  fn span(&self, start: usize, end: usize, line: usize, column: usize) -> Span {
+    Span {
+      file: self.file,
+      start,
+      end,
+      line,
+      column,
+    }
+  }
+
+  /// The span of the token being scanned.
+  fn token_span(&self) -> Span {
+    self.span(self.start, self.current, self.start_line, self.start_column)
+  }
+
   fn make_token(&self, typ: TokenType) -> ScanResult {
     Ok(Token {
       typ,
-      start: self.start,
-      end: self.current,
-      line: self.line,
+      span: self.token_span(),
     })
   }
 
   /// Make a token with further check. If the next character matches `expected`, make token with `typ1`, else `typ2`.
-  fn make_token_with_check(&mut self, typ1: TokenType, typ2: TokenType, expected: char) -> ScanResult {
+  fn make_token_with_check(&mut self, typ1: TokenType, typ2: TokenType, expected: u8) -> ScanResult {
     if self.is_match(expected) {
       self.make_token(typ1)
     } else {
@@ -199,38 +330,146 @@ impl Scanner {
   pub fn scan_token(&mut self) -> ScanResult {
     use TokenType::*;
 
-    self.skip_whitespace();
+    self.skip_whitespace()?;
 
     self.start = self.current;
+    self.start_line = self.line;
+    self.start_column = self.column_at(self.start);
     if self.is_at_end() {
       return self.make_token(Eof);
     }
 
     let ch = self.advance();
     match ch {
-      '(' => self.make_token(LParen),
-      ')' => self.make_token(RParen),
-      '{' => self.make_token(LBrace),
-      '}' => self.make_token(RBrace),
-      ';' => self.make_token(Semicolon),
-      ',' => self.make_token(Comma),
-      '.' => self.make_token(Dot),
-      '-' => self.make_token(Minus),
-      '+' => self.make_token(Plus),
-      '/' => self.make_token(Slash),
-      '*' => self.make_token(Star),
-      '!' => self.make_token_with_check(EBang, Bang, '='),
-      '=' => self.make_token_with_check(EEqual, Equal, '='),
-      '<' => self.make_token_with_check(Le, Lt, '='),
-      '>' => self.make_token_with_check(Ge, Gt, '='),
-      '"' => self.scan_string(),
+      b'(' => self.make_token(LParen),
+      b')' => self.make_token(RParen),
+      b'{' => self.make_token(LBrace),
+      b'}' => self.make_token(RBrace),
+      b';' => self.make_token(Semicolon),
+      b',' => self.make_token(Comma),
+      b'.' => self.make_token(Dot),
+      b'-' => self.make_token(Minus),
+      b'+' => self.make_token(Plus),
+      b'/' => self.make_token(Slash),
+      b'*' => self.make_token(Star),
+      b'!' => self.make_token_with_check(EBang, Bang, b'='),
+      b'=' => self.make_token_with_check(EEqual, Equal, b'='),
+      b'<' => self.make_token_with_check(Le, Lt, b'='),
+      b'>' => self.make_token_with_check(Ge, Gt, b'='),
+      b'"' => self.scan_string(),
       c if c.is_ascii_digit() => self.scan_number(),
-      c if Self::is_legal_ident(c) => self.scan_ident(),
-      _ => Err(CompileError::new(self.line, ch.into(), "unexpect character".into())),
+      c if c.is_ascii_alphabetic() || c == b'_' => self.scan_ident(),
+      _ => {
+        // step back to the start of a multi-byte character
+        self.current = self.start;
+        let c = self.peek_char().unwrap();
+        self.current += c.len_utf8();
+        if Self::is_ident_start(c) {
+          return self.scan_ident();
+        }
+        let literal = c.escape_debug().to_string();
+        Err(CompileError::new(
+          self.token_span(),
+          literal,
+          "unexpect character".into(),
+        ))
+      }
     }
   }
 }
 
+/// Parse a number literal: a decimal one with an optional fraction and exponent like `2.5E-3`,
+/// or an integer with a `0x`, `0b` or `0o` prefix. Digits may be separated by single `_`s.
+pub fn parse_number(literal: &str) -> Result<f64, &'static str> {
+  let radix = match literal.get(..2) {
+    Some("0x" | "0X") => 16,
+    Some("0b" | "0B") => 2,
+    Some("0o" | "0O") => 8,
+    _ => 10,
+  };
+  if radix != 10 {
+    let digits = &literal[2..];
+    check_digits(digits, radix)?;
+    let value = digits
+      .chars()
+      .filter_map(|c| c.to_digit(radix))
+      .fold(0.0, |value, digit| value * radix as f64 + digit as f64);
+    return Ok(value);
+  }
+  let (mantissa, exponent) = match literal.find(['e', 'E']) {
+    Some(i) => (&literal[..i], Some(&literal[i + 1..])),
+    None => (literal, None),
+  };
+  let (integer, fraction) = match mantissa.split_once('.') {
+    Some((integer, fraction)) => (integer, Some(fraction)),
+    None => (mantissa, None),
+  };
+  check_digits(integer, 10)?;
+  fraction.map_or(Ok(()), |fraction| check_digits(fraction, 10))?;
+  if let Some(exponent) = exponent {
+    let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+    check_digits(exponent, 10).map_err(|_| "malformed exponent in number literal")?;
+  }
+  literal.replace('_', "").parse().map_err(|_| "malformed number literal")
+}
+
+/// Check `digits` are in `radix`, with `_` only between two digits.
+fn check_digits(digits: &str, radix: u32) -> Result<(), &'static str> {
+  if digits.is_empty() {
+    Err("missing digits in number literal")
+  } else if !digits.chars().all(|c| c == '_' || c.is_digit(radix)) {
+    Err("invalid digit in number literal")
+  } else if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+    Err("'_' must be between digits in number literal")
+  } else {
+    Ok(())
+  }
+}
+
+/// Resolve the escape sequences in the content of a string literal.  
+/// On error, return the byte range of the invalid escape sequence in `raw` and the reason.
+pub fn unescape(raw: &str) -> Result<String, (std::ops::Range<usize>, &'static str)> {
+  let mut value = String::with_capacity(raw.len());
+  let mut chars = raw.char_indices().peekable();
+  while let Some((start, c)) = chars.next() {
+    if c != '\\' {
+      value.push(c);
+      continue;
+    }
+    let escaped = match chars.next() {
+      Some((_, 'n')) => '\n',
+      Some((_, 't')) => '\t',
+      Some((_, 'r')) => '\r',
+      Some((_, '\\')) => '\\',
+      Some((_, '"')) => '"',
+      Some((_, '0')) => '\0',
+      // `\u{X}` with 1 to 6 hex digits
+      Some((i, 'u')) => {
+        let rest = &raw[i + 1..];
+        let close = rest.char_indices().take(8).find(|(_, c)| *c == '}');
+        let (Some("{"), Some((close, _))) = (rest.get(..1), close) else {
+          return Err((start..i + 1, "invalid unicode escape, expect '\\u{...}'"));
+        };
+        let end = i + 1 + close + 1;
+        while chars.next_if(|(j, _)| *j < end).is_some() {}
+        let digits = &rest[1..close];
+        // checked before parsing, since `from_str_radix` accepts a leading sign
+        let valid = (1..=6).contains(&digits.len()) && digits.bytes().all(|c| c.is_ascii_hexdigit());
+        let code = u32::from_str_radix(digits, 16).ok().filter(|_| valid);
+        match code.map(char::from_u32) {
+          Some(Some(c)) => c,
+          Some(None) => return Err((start..end, "invalid unicode code point")),
+          None => return Err((start..end, "invalid unicode escape, expect 1 to 6 hex digits")),
+        }
+      }
+      Some((i, c)) => return Err((start..i + c.len_utf8(), "invalid escape sequence")),
+      None => return Err((start..raw.len(), "invalid escape sequence")),
+    };
+    value.push(escaped);
+  }
+  Ok(value)
+}
+
 #[cfg(test)]
 mod scanner_test {
   use super::*;
@@ -251,4 +490,212 @@ mod scanner_test {
       }
     }
   }
+
+  #[test]
+  fn test_position() {
+    let mut scanner = Scanner::new("var a;\n  print \"x\ny\" + a;".into());
+    let mut positions = Vec::new();
+    loop {
+      let token = scanner.scan_token().unwrap();
+      positions.push((token.span.line, token.span.column));
+      if token.typ == TokenType::Eof {
+        break;
+      }
+    }
+    // a string spanning lines is positioned at its opening quote
+    let expected = [(1, 1), (1, 5), (1, 6), (2, 3), (2, 9), (3, 4), (3, 6), (3, 7), (3, 8)];
+    assert_eq!(positions, expected);
+  }
+
+  fn scan_all(source: &str) -> Result<Vec<Token>, CompileError> {
+    let mut scanner = Scanner::new(source.into());
+    let mut tokens = Vec::new();
+    loop {
+      let token = scanner.scan_token()?;
+      if token.typ == TokenType::Eof {
+        return Ok(tokens);
+      }
+      tokens.push(token);
+    }
+  }
+
+  #[test]
+  fn test_escape() {
+    let unescaped = unescape;
+    assert_eq!(
+      unescaped(r#"a\n\t\r\\\"\0\u{1F600}\u{41}"#).unwrap(),
+      "a\n\t\r\\\"\0\u{1F600}A"
+    );
+    assert_eq!(unescaped(r"ab\q").unwrap_err(), (2..4, "invalid escape sequence"));
+    assert!(unescaped(r"\u41").is_err());
+    assert!(unescaped(r"\u{}").is_err());
+    assert!(unescaped(r"\u{1234567}").is_err());
+    assert!(unescaped(r"\u{+41}").is_err());
+    assert_eq!(
+      unescaped(r"\u{D800}").unwrap_err(),
+      (0..8, "invalid unicode code point")
+    );
+
+    // an escaped quote does not end the string
+    let tokens = scan_all(r#"print "say \"hi\"";"#).unwrap();
+    assert_eq!(tokens[1].span.end - tokens[1].span.start, 12);
+  }
+
+  #[test]
+  fn test_escape_error() {
+    let error = |source: &str| scan_all(source).unwrap_err().to_string();
+    assert_eq!(
+      error(r#"var s = "ab\qc";"#),
+      r"[line 1, column 12] Error Error at \q: invalid escape sequence"
+    );
+    // the column is counted from the last line of a multi-line string
+    assert_eq!(
+      error("var s = \"a\n  b\\u{110000}\";"),
+      r"[line 2, column 4] Error Error at \u{110000}: invalid unicode code point"
+    );
+    assert!(error(r#"print "a\";"#).contains("unterminated string"));
+  }
+
+  #[test]
+  fn test_multi_line_string() {
+    let tokens = scan_all("print \"a\nb\nc\";\nvar x;").unwrap();
+    let lines: Vec<_> = tokens.iter().map(|t| t.span.line).collect();
+    assert_eq!(lines, [1, 1, 3, 4, 4, 4]);
+  }
+
+  #[test]
+  fn test_number() {
+    let number = |source: &str| {
+      let tokens = scan_all(source).unwrap();
+      assert_eq!(tokens.len(), 1, "{}", source);
+      parse_number(&tokens[0].get_literal(source)).unwrap()
+    };
+    assert_eq!(number("123"), 123.0);
+    assert_eq!(number("123.45"), 123.45);
+    assert_eq!(number("1e10"), 1e10);
+    assert_eq!(number("2.5E-3"), 2.5e-3);
+    assert_eq!(number("1e+2"), 100.0);
+    assert_eq!(number("0xFF"), 255.0);
+    assert_eq!(number("0Xff"), 255.0);
+    assert_eq!(number("0b1010"), 10.0);
+    assert_eq!(number("0o17"), 15.0);
+    assert_eq!(number("1_000_000"), 1e6);
+    assert_eq!(number("0xFFFF_FFFF"), u32::MAX as f64);
+    assert_eq!(number("1_0.0_1e1_0"), 10.01e10);
+
+    // the dot of a method call is not a fraction
+    let typs: Vec<_> = scan_all("1.x 2.").unwrap().iter().map(|t| t.typ).collect();
+    use TokenType::*;
+    assert_eq!(typs, [Num, Dot, Ident, Num, Dot]);
+    // a hex literal has no exponent
+    let typs: Vec<_> = scan_all("0xE+1").unwrap().iter().map(|t| t.typ).collect();
+    assert_eq!(typs, [Num, Plus, Num]);
+  }
+
+  #[test]
+  fn test_malformed_number() {
+    let error = |source: &str| scan_all(source).unwrap_err().to_string();
+    for (source, msg) in [
+      ("0x", "missing digits"),
+      ("0b", "missing digits"),
+      ("0b102", "invalid digit"),
+      ("0o8", "invalid digit"),
+      ("0xFG", "invalid digit"),
+      ("12ab", "invalid digit"),
+      ("1__0", "'_' must be between digits"),
+      ("1_", "'_' must be between digits"),
+      ("1_.5", "'_' must be between digits"),
+      ("0x_1", "'_' must be between digits"),
+      ("1e", "malformed exponent"),
+      ("1e_1", "malformed exponent"),
+      ("1e3.5", "malformed exponent"),
+    ] {
+      let e = error(&format!("print {};", source));
+      assert!(e.starts_with("[line 1, column 7]"), "{}: {}", source, e);
+      assert!(e.contains(&format!("at {}: {}", source, msg)), "{}: {}", source, e);
+    }
+  }
+
+  #[test]
+  fn test_comment() {
+    let source = "// line\nprint 4 / 2; /* block\n /* nested\n */ still a comment */ var /**/ x; // tail";
+    let tokens = scan_all(source).unwrap();
+    let found: Vec<_> = tokens.iter().map(|t| (t.typ, t.span.line)).collect();
+    use TokenType::*;
+    let expected = [
+      (Print, 2),
+      (Num, 2),
+      (Slash, 2),
+      (Num, 2),
+      (Semicolon, 2),
+      (Var, 4),
+      (Ident, 4),
+      (Semicolon, 4),
+    ];
+    assert_eq!(found, expected);
+
+    let e = scan_all("print 1;\n  /* a /* b */\n\n").unwrap_err();
+    assert_eq!(
+      e.to_string(),
+      "[line 2, column 3] Error Error at /*: unterminated block comment"
+    );
+    // `*/` alone is not a comment
+    let typs: Vec<_> = scan_all("*/").unwrap().iter().map(|t| t.typ).collect();
+    assert_eq!(typs, [Star, Slash]);
+  }
+
+  #[test]
+  fn test_unicode() {
+    let source = "var été = \"naïve\"; print 名前 + _x1;";
+    let tokens = scan_all(source).unwrap();
+    use TokenType::*;
+    let found: Vec<_> = tokens
+      .iter()
+      .map(|t| (t.typ, t.get_literal(source), t.span.column))
+      .collect();
+    let expected = [
+      (Var, "var", 1),
+      (Ident, "été", 5),
+      (Equal, "=", 9),
+      (Str, "naïve", 11),
+      (Semicolon, ";", 18),
+      (Print, "print", 20),
+      (Ident, "名前", 26),
+      (Plus, "+", 29),
+      (Ident, "_x1", 31),
+      (Semicolon, ";", 34),
+    ];
+    assert_eq!(
+      found,
+      expected.map(|(typ, literal, column)| (typ, literal.to_string(), column))
+    );
+    // spans are byte offsets
+    assert_eq!((tokens[1].span.start, tokens[1].span.end), (4, 9));
+
+    // a keyword followed by a non-ASCII identifier character is an identifier
+    assert_eq!(scan_all("varé").unwrap()[0].typ, Ident);
+    let e = scan_all("print 1 € 2;").unwrap_err();
+    assert_eq!(e.to_string(), "[line 1, column 9] Error Error at €: unexpect character");
+  }
+
+  #[test]
+  fn test_nul() {
+    // an embedded NUL does not end the source
+    let e = scan_all("print 1;\0print 2;").unwrap_err();
+    assert_eq!(
+      e.to_string(),
+      r"[line 1, column 9] Error Error at \0: unexpect character"
+    );
+    let mut scanner = Scanner::new("a\0b".into());
+    let typs: Vec<_> = std::iter::from_fn(|| Some(scanner.scan_token().map(|t| t.typ)))
+      .take(4)
+      .collect();
+    assert!(matches!(
+      typs[..],
+      [Ok(TokenType::Ident), Err(_), Ok(TokenType::Ident), Ok(TokenType::Eof)]
+    ));
+    // NUL in strings and comments is kept or skipped like any character
+    let tokens = scan_all("\"a\0b\" // \0\n/* \0 */").unwrap();
+    assert_eq!(tokens.len(), 1);
+  }
 }
@@ -0,0 +1,133 @@
+//! Source positions, and the source files they point into.
+
+/// A source file registered in a `SourceMap`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FileId(u32);
+
+/// A range of source code.
+/// `start` and `end` are byte offsets, `line` and `column` are the position of `start` counted from 1,
+/// the column in characters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+  pub file: FileId,
+  pub start: usize,
+  pub end: usize,
+  pub line: usize,
+  pub column: usize,
+}
+
+struct SourceFile {
+  name: String,
+  source: String,
+}
+
+/// The source files spans point into, used to turn a span back into a readable position.
+#[derive(Default)]
+pub struct SourceMap {
+  files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Register a source file. The source may be empty if it is not available, like for compiled scripts.
+  pub fn add(&mut self, name: String, source: String) -> FileId {
+    self.files.push(SourceFile { name, source });
+    FileId((self.files.len() - 1) as u32)
+  }
+
+  pub fn name(&self, file: FileId) -> &str {
+    &self.files[file.0 as usize].name
+  }
+
+  pub fn source(&self, file: FileId) -> &str {
+    &self.files[file.0 as usize].source
+  }
+
+  /// Format the start of `span` as `file:line:col`.
+  pub fn location(&self, span: &Span) -> String {
+    format!("{}:{}:{}", self.name(span.file), span.line, span.column)
+  }
+
+  /// The line `span` starts in, without the line break. `None` if the span is not in the source,
+  /// or the source is not available.
+  pub fn line_text(&self, span: &Span) -> Option<&str> {
+    let source = self.source(span.file);
+    if source.is_empty() {
+      return None;
+    }
+    let line_start = source.get(..span.start)?.rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[span.start..].find('\n').map_or(source.len(), |i| span.start + i);
+    Some(source[line_start..line_end].trim_end_matches('\r'))
+  }
+
+  /// Format the location of `span` followed by its line, with the span underlined if the source is available.
+  ///
+  /// ```text
+  ///  --> test.lox:2:7
+  ///   |
+  /// 2 | print x;
+  ///   |       ^
+  /// ```
+  pub fn snippet(&self, span: &Span) -> String {
+    let mut snippet = format!(" --> {}\n", self.location(span));
+    let Some(text) = self.line_text(span) else {
+      return snippet;
+    };
+    let gutter = " ".repeat(span.line.to_string().len());
+    // keep the tabs so the marker lines up with the text
+    let indent: String = text
+      .chars()
+      .take(span.column.saturating_sub(1))
+      .map(|c| if c == '\t' { '\t' } else { ' ' })
+      .collect();
+    let rest = text.chars().count().saturating_sub(span.column.saturating_sub(1));
+    let width = self
+      .source(span.file)
+      .get(span.start..span.end)
+      .map_or(1, |s| s.chars().count());
+    let marker = "^".repeat(width.clamp(1, rest.max(1)));
+    snippet += &format!(
+      "{} |\n{} | {}\n{} | {}{}\n",
+      gutter, span.line, text, gutter, indent, marker
+    );
+    snippet
+  }
+}
+
+#[cfg(test)]
+mod span_test {
+  use super::*;
+
+  #[test]
+  fn test_snippet() {
+    let mut map = SourceMap::new();
+    let file = map.add("test.lox".into(), "var a;\n\tprint \"é\" + b;\n".into());
+    let span = Span {
+      file,
+      start: 14,
+      end: 18,
+      line: 2,
+      column: 8,
+    };
+    assert_eq!(map.location(&span), "test.lox:2:8");
+    assert_eq!(map.line_text(&span), Some("\tprint \"é\" + b;"));
+    assert_eq!(
+      map.snippet(&span),
+      " --> test.lox:2:8\n  |\n2 | \tprint \"é\" + b;\n  | \t      ^^^\n"
+    );
+
+    // the source of a compiled script is not available
+    let compiled = map.add("test.loxc".into(), String::new());
+    let span = Span {
+      file: compiled,
+      start: 0,
+      end: 0,
+      ..span
+    };
+    assert_eq!(map.line_text(&span), None);
+    assert_eq!(map.snippet(&span), " --> test.loxc:2:8\n");
+  }
+}
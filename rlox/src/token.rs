@@ -1,4 +1,4 @@
-use crate::def_tokentype;
+use crate::span::Span;
 
 #[repr(u8)]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
@@ -36,6 +36,8 @@ def_tokentype!(
   Eof
 );
 
+// The variants are generated by `def_tokentype!`, so `#[default]` can not be attached to `Eof`.
+#[allow(clippy::derivable_impls)]
 impl std::default::Default for TokenType {
   fn default() -> Self {
     TokenType::Eof
@@ -45,26 +47,23 @@ impl std::default::Default for TokenType {
 #[derive(Default, Debug)]
 pub struct Token {
   pub typ: TokenType,
-  /// the start position of literal in specific source (`Vec<char>`)
-  pub start: usize,
-  /// the end position (next) of literal in specific source (`Vec<char>`)
-  pub end: usize,
-  pub line: usize,
+  /// Where the token is in the source, a string token spans its quotes.
+  pub span: Span,
 }
 
 impl Token {
   /// Format the type, literal and line to readable string.
-  pub fn to_string(&self, source: &[char]) -> String {
-    format!("[{}: '{}' | {}]", self.typ, self.get_literal(source), self.line)
+  pub fn to_string(&self, source: &str) -> String {
+    format!("[{}: '{}' | {}]", self.typ, self.get_literal(source), self.span.line)
   }
 
   /// Retrieve the literal from source
-  pub fn get_literal(&self, source: &[char]) -> String {
+  pub fn get_literal(&self, source: &str) -> String {
     // if the toketype is STR, trip the wrapping quote.
     if let TokenType::Str = self.typ {
-      unsafe { source.get_unchecked(self.start + 1..self.end - 1).iter().collect() }
+      source[self.span.start + 1..self.span.end - 1].into()
     } else {
-      unsafe { source.get_unchecked(self.start..self.end).iter().collect() }
+      source[self.span.start..self.span.end].into()
     }
   }
 }
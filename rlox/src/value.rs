@@ -1,30 +1,63 @@
-use std::rc::Rc;
-#[derive(Clone)]
+use crate::memory::{Gc, Heap};
+use crate::object::{BoundMethod, Class, Closure, Function, Instance};
+
+/// Objects are referred by `Gc` handles, so a `Value` is cheap to copy.
+#[derive(Clone, Copy, Default)]
 pub enum Value {
   Number(f64),
   Boolean(bool),
-  Str(Rc<String>),
+  Str(Gc<String>),
+  Function(Gc<Function>),
+  Closure(Gc<Closure>),
+  Class(Gc<Class>),
+  Instance(Gc<Instance>),
+  BoundMethod(Gc<BoundMethod>),
+  #[default]
   Nil,
 }
 
-impl std::default::Default for Value {
-  fn default() -> Self {
-    Self::Nil
+impl Value {
+  /// Format the value to readable string, objects are looked up in `heap`.
+  pub fn to_string(self, heap: &Heap) -> String {
+    match self {
+      Value::Number(n) => format!("{}", n),
+      Value::Boolean(b) => format!("{}", b),
+      Value::Str(s) => heap.get(s).clone(),
+      Value::Function(fun) => format!("{}", heap.get(fun)),
+      Value::Closure(closure) => format!("{}", heap.get(heap.get(closure).function)),
+      Value::Class(class) => heap.get(class).name.clone(),
+      Value::Instance(instance) => format!("{} instance", heap.get(heap.get(instance).class).name),
+      Value::BoundMethod(bound) => {
+        let closure = heap.get(heap.get(bound).method);
+        format!("{}", heap.get(closure.function))
+      }
+      Value::Nil => "nil".into(),
+    }
   }
-}
 
-impl std::fmt::Display for Value {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+  /// Like `to_string`, but strings are quoted and escaped so the value is told apart from other types
+  /// and never spans lines.
+  pub fn to_quoted_string(self, heap: &Heap) -> String {
     match self {
-      Value::Number(n) => write!(f, "{}", n),
-      Value::Boolean(b) => write!(f, "{}", b),
-      Value::Str(s) => write!(f, "{}", s),
-      Value::Nil => write!(f, "nil"),
+      Value::Str(s) => format!("{:?}", heap.get(s)),
+      value => value.to_string(heap),
+    }
+  }
+
+  /// The name of the value's type, used in runtime errors.
+  pub fn type_name(&self) -> &'static str {
+    match self {
+      Value::Number(_) => "number",
+      Value::Boolean(_) => "boolean",
+      Value::Str(_) => "string",
+      Value::Function(_) | Value::Closure(_) => "function",
+      Value::Class(_) => "class",
+      Value::Instance(_) => "instance",
+      Value::BoundMethod(_) => "method",
+      Value::Nil => "nil",
     }
   }
-}
 
-impl Value {
   pub fn as_number(&self) -> Option<f64> {
     if let Self::Number(n) = self {
       Some(*n)
@@ -49,9 +82,49 @@ impl Value {
     matches!(self, Value::Str(_))
   }
 
-  pub fn as_string(&self) -> Option<Rc<String>> {
+  pub fn as_string(&self) -> Option<Gc<String>> {
     if let Value::Str(s) = self {
-      Some(s.clone())
+      Some(*s)
+    } else {
+      None
+    }
+  }
+
+  pub fn as_function(&self) -> Option<Gc<Function>> {
+    if let Value::Function(fun) = self {
+      Some(*fun)
+    } else {
+      None
+    }
+  }
+
+  pub fn as_closure(&self) -> Option<Gc<Closure>> {
+    if let Value::Closure(closure) = self {
+      Some(*closure)
+    } else {
+      None
+    }
+  }
+
+  pub fn as_class(&self) -> Option<Gc<Class>> {
+    if let Value::Class(class) = self {
+      Some(*class)
+    } else {
+      None
+    }
+  }
+
+  pub fn as_instance(&self) -> Option<Gc<Instance>> {
+    if let Value::Instance(instance) = self {
+      Some(*instance)
+    } else {
+      None
+    }
+  }
+
+  pub fn as_bound_method(&self) -> Option<Gc<BoundMethod>> {
+    if let Value::BoundMethod(bound) = self {
+      Some(*bound)
     } else {
       None
     }
@@ -66,7 +139,8 @@ impl Value {
     }
   }
 
-  /// Whether two Lox Value are equal.
+  /// Whether two Lox Value are equal. Objects are compared by identity, which also holds for strings
+  /// since they are all interned.
   pub fn equals(&self, other: &Self) -> bool {
     if std::mem::discriminant(self) != std::mem::discriminant(other) {
       return false;
@@ -76,6 +150,11 @@ impl Value {
       Self::Number(n) => *n == other.as_number().unwrap(),
       Self::Boolean(b) => *b == other.as_bool().unwrap(),
       Self::Str(s) => *s == other.as_string().unwrap(),
+      Self::Function(fun) => *fun == other.as_function().unwrap(),
+      Self::Closure(closure) => *closure == other.as_closure().unwrap(),
+      Self::Class(class) => *class == other.as_class().unwrap(),
+      Self::Instance(instance) => *instance == other.as_instance().unwrap(),
+      Self::BoundMethod(bound) => *bound == other.as_bound_method().unwrap(),
     }
   }
 }
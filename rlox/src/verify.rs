@@ -0,0 +1,322 @@
+use crate::chunk::OpCode;
+use crate::custom_error::{VerifyError, VerifyErrorKind};
+use crate::memory::Heap;
+use crate::object::Function;
+use crate::value::Value;
+use crate::vm::MAX_STACK;
+
+/// Check the script `function` and every function nested in its constants could be executed without
+/// reading out of the bounds of the chunk, the constant pool, the stack or the upvalues.  
+/// Return all the errors found rather than stopping at the first one.
+pub fn verify(function: &Function, heap: &Heap) -> Result<(), Vec<VerifyError>> {
+  let mut errors = Vec::new();
+  // the script is called with the closure itself in slot 0
+  let mut verifier = Verifier::new(function, heap, &mut errors);
+  // and without arguments or captured variables
+  if function.arity != 0 {
+    verifier.error(0, VerifyErrorKind::ScriptArity(function.arity));
+  }
+  if !function.upvalues.is_empty() {
+    verifier.error(0, VerifyErrorKind::ScriptUpvalues(function.upvalues.len()));
+  }
+  verifier.verify();
+  if errors.is_empty() {
+    Ok(())
+  } else {
+    Err(errors)
+  }
+}
+
+struct Verifier<'a> {
+  function: &'a Function,
+  heap: &'a Heap,
+  /// The stack depth relative to the frame before each instruction, `None` if it is not reached yet.
+  depths: Vec<Option<usize>>,
+  errors: &'a mut Vec<VerifyError>,
+}
+
+impl<'a> Verifier<'a> {
+  fn new(function: &'a Function, heap: &'a Heap, errors: &'a mut Vec<VerifyError>) -> Self {
+    Self {
+      function,
+      heap,
+      depths: vec![None; function.chunk.len()],
+      errors,
+    }
+  }
+
+  fn error(&mut self, index: usize, kind: VerifyErrorKind) {
+    self.errors.push(VerifyError {
+      function: self.function.to_string(),
+      index,
+      kind,
+    });
+  }
+
+  fn verify(&mut self) {
+    let chunk = &self.function.chunk;
+    if chunk.lines().iter().map(|run| run.count).sum::<usize>() != chunk.len() {
+      self.error(0, VerifyErrorKind::LineTableMismatch);
+    }
+    if !matches!(chunk.code().last(), Some(OpCode::Return)) {
+      self.error(chunk.len().saturating_sub(1), VerifyErrorKind::MissingReturn);
+    }
+    for index in 0..chunk.len() {
+      self.check_operands(index);
+    }
+    self.check_stack();
+    for constant in chunk.constants() {
+      if let Value::Function(nested) = constant {
+        let nested = self.heap.get(*nested);
+        Verifier::new(nested, self.heap, self.errors).verify();
+      }
+    }
+  }
+
+  /// Check the constants and upvalues referred by the instruction at `index`.
+  fn check_operands(&mut self, index: usize) {
+    use OpCode::*;
+    let chunk = &self.function.chunk;
+    let constant = |i: usize, expected: &'static str, is_expected: fn(&Value) -> bool| match chunk.constants().get(i) {
+      None => Some(VerifyErrorKind::ConstantOutOfRange(i)),
+      Some(value) if !is_expected(value) => Some(VerifyErrorKind::ConstantType { index: i, expected }),
+      Some(_) => None,
+    };
+    let op = chunk.fetch(index);
+    let error = match (op, op.constant_index()) {
+      (Constant(_) | ConstantLong(_), Some(i)) => constant(i, "constant", |_| true),
+      (Closure(_) | ClosureLong(_), Some(i)) => constant(i, "function", |value| matches!(value, Value::Function(_))),
+      // the other instructions refer to a name
+      (_, Some(i)) => constant(i, "string", Value::is_string),
+      (GetUpvalue(i) | SetUpvalue(i), _) if usize::from(i) >= self.function.upvalues.len() => {
+        Some(VerifyErrorKind::UpvalueOutOfRange(i))
+      }
+      _ => None,
+    };
+    if let Some(kind) = error {
+      self.error(index, kind);
+    }
+  }
+
+  /// Walk every path through the chunk, checking the stack depth before each instruction is the same
+  /// along all the paths, and that no instruction pops more than the stack holds.
+  fn check_stack(&mut self) {
+    let len = self.function.chunk.len();
+    if len == 0 {
+      return;
+    }
+    // the callee and the arguments
+    let mut pending = vec![(0, self.function.arity + 1)];
+    while let Some((index, depth)) = pending.pop() {
+      match self.depths[index] {
+        Some(expected) if expected != depth => {
+          self.error(
+            index,
+            VerifyErrorKind::InconsistentStackDepth { expected, found: depth },
+          );
+          continue;
+        }
+        Some(_) => continue,
+        None => self.depths[index] = Some(depth),
+      }
+      let op = self.function.chunk.fetch(index);
+      let (needed, pushed) = self.stack_effect(op);
+      if depth < needed {
+        self.error(index, VerifyErrorKind::StackUnderflow { depth, needed });
+        continue;
+      }
+      let after = depth - needed + pushed;
+      if after > MAX_STACK {
+        self.error(index, VerifyErrorKind::StackOverflow(after));
+        continue;
+      }
+      self.check_slots(index, op, depth);
+
+      let mut successors = Vec::with_capacity(2);
+      match op {
+        OpCode::Return => {}
+        OpCode::Jump(offset) => successors.push(Some(index + 1 + usize::from(offset))),
+        OpCode::JumpIfFalse(offset) => {
+          successors.push(Some(index + 1));
+          successors.push(Some(index + 1 + usize::from(offset)));
+        }
+        OpCode::Loop(offset) => successors.push((index + 1).checked_sub(offset.into())),
+        _ => successors.push(Some(index + 1)),
+      }
+      for successor in successors {
+        match successor {
+          Some(target) if target < len => pending.push((target, after)),
+          // falling off the end is reported as a missing return
+          Some(target) if target == len && !matches!(op, OpCode::Jump(_) | OpCode::JumpIfFalse(_)) => {}
+          _ => self.error(index, VerifyErrorKind::JumpOutOfRange),
+        }
+      }
+    }
+  }
+
+  /// Check the stack slots and upvalues the instruction at `index` accesses exist, given the stack `depth`.
+  fn check_slots(&mut self, index: usize, op: OpCode, depth: usize) {
+    match op {
+      OpCode::GetLocal(slot) | OpCode::SetLocal(slot) if usize::from(slot) >= depth => {
+        self.error(index, VerifyErrorKind::LocalOutOfRange(slot));
+      }
+      OpCode::Closure(_) | OpCode::ClosureLong(_) => {
+        let Some(Value::Function(nested)) = op.constant_index().and_then(|i| self.function.chunk.constants().get(i))
+        else {
+          return;
+        };
+        // a closure captures the locals of this frame, or the upvalues of the closure being executed
+        for upvalue in &self.heap.get(*nested).upvalues {
+          if upvalue.is_local && usize::from(upvalue.index) >= depth {
+            self.error(index, VerifyErrorKind::LocalOutOfRange(upvalue.index));
+          } else if !upvalue.is_local && usize::from(upvalue.index) >= self.function.upvalues.len() {
+            self.error(index, VerifyErrorKind::UpvalueOutOfRange(upvalue.index));
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+
+  /// The number of values the opcode needs on the stack, and the number of values it leaves in their place.
+  fn stack_effect(&self, op: OpCode) -> (usize, usize) {
+    use OpCode::*;
+    match op {
+      Constant(_) | ConstantLong(_) | True | False | Nil | GetGlobal(_) | GetGlobalLong(_) | GetLocal(_)
+      | GetUpvalue(_) | Closure(_) | ClosureLong(_) | Class(_) | ClassLong(_) => (0, 1),
+      Neg | Not | GetProperty(_) | GetPropertyLong(_) | SetGlobal(_) | SetGlobalLong(_) | SetLocal(_)
+      | SetUpvalue(_) | JumpIfFalse(_) => (1, 1),
+      Add | Sub | Mul | Div | Greater | Less | Equal | NotEqual | GreaterEqual | LessEqual | SetProperty(_)
+      | SetPropertyLong(_) | GetSuper(_) | GetSuperLong(_) => (2, 1),
+      Print | Pop | DefineGlobal(_) | DefineGlobalLong(_) | CloseUpvalue | Return => (1, 0),
+      // the class is left on the stack
+      Method(_) | MethodLong(_) | Inherit => (2, 1),
+      Jump(_) | Loop(_) => (0, 0),
+      // the callee or the receiver with the arguments are replaced by the result
+      Call(arg_count) | Invoke(_, arg_count) | InvokeLong(_, arg_count) => (usize::from(arg_count) + 1, 1),
+      // the superclass is popped as well
+      SuperInvoke(_, arg_count) | SuperInvokeLong(_, arg_count) => (usize::from(arg_count) + 2, 1),
+    }
+  }
+}
+
+#[cfg(test)]
+mod verify_test {
+  use super::*;
+  use crate::chunk::{Chunk, LineRun};
+  use crate::compile::{Compiler, OptLevel};
+  use crate::object::UpvalueDesc;
+  use crate::span::Span;
+
+  /// Build a function of `arity` with `code`, all in line 1.
+  fn function(arity: usize, code: Vec<OpCode>, constants: Vec<Value>) -> Function {
+    let span = Span {
+      line: 1,
+      column: 1,
+      ..Span::default()
+    };
+    let lines = vec![LineRun {
+      span,
+      count: code.len(),
+    }];
+    let mut function = Function::new(Some("f".into()));
+    function.arity = arity;
+    function.chunk = Chunk::from_parts(code, constants, lines);
+    function
+  }
+
+  fn errors(function: &Function) -> Vec<VerifyErrorKind> {
+    let heap = Heap::new();
+    verify(function, &heap)
+      .err()
+      .unwrap_or_default()
+      .into_iter()
+      .map(|e| e.kind)
+      .collect()
+  }
+
+  #[test]
+  fn test_compiled_code() {
+    let source = "
+      fun outer(a) { var b = a; fun inner() { b = b + 1; return b; } return inner; }
+      class A { init(x) { this.x = x; } get() { return this.x; } }
+      class B < A { get() { var f = super.get; return f() + super.get(); } }
+      for (var i = 0; i < 3 and !(i >= 2) or i == nil; i = i + 1) { if (i) print i; else { var c; print c; } }
+      while (false) { var a = 1; { var b = a; print b; } }
+      print B(outer(1)()).get();";
+    for opt_level in [OptLevel::O0, OptLevel::O1] {
+      let mut heap = Heap::new();
+      let script = Compiler::with_opt_level(source.into(), &mut heap, opt_level)
+        .compile()
+        .unwrap();
+      assert_eq!(verify(&script, &heap), Ok(()));
+    }
+  }
+
+  #[test]
+  fn test_reject() {
+    use OpCode::*;
+    assert_eq!(
+      errors(&function(0, vec![Nil], vec![])),
+      [VerifyErrorKind::MissingReturn]
+    );
+    assert_eq!(
+      errors(&function(0, vec![Constant(1), Return], vec![Value::Nil])),
+      [VerifyErrorKind::ConstantOutOfRange(1)]
+    );
+    assert_eq!(
+      errors(&function(0, vec![GetGlobal(0), Return], vec![Value::Nil])),
+      [VerifyErrorKind::ConstantType {
+        index: 0,
+        expected: "string"
+      }]
+    );
+    assert_eq!(
+      errors(&function(0, vec![Jump(5), Nil, Return], vec![])),
+      [VerifyErrorKind::JumpOutOfRange]
+    );
+    assert_eq!(
+      errors(&function(0, vec![Loop(5), Return], vec![])),
+      [VerifyErrorKind::JumpOutOfRange]
+    );
+    assert_eq!(
+      errors(&function(0, vec![Nil, GetLocal(2), Return], vec![])),
+      [VerifyErrorKind::LocalOutOfRange(2)]
+    );
+    assert_eq!(
+      errors(&function(0, vec![Pop, Pop, Nil, Return], vec![])),
+      [VerifyErrorKind::StackUnderflow { depth: 0, needed: 1 }]
+    );
+    // one path pushes a value the other does not
+    let code = vec![True, JumpIfFalse(1), Nil, Pop, Return];
+    assert_eq!(
+      errors(&function(0, code, vec![])),
+      [VerifyErrorKind::InconsistentStackDepth { expected: 2, found: 3 }]
+    );
+    // a loop pushing a value on every iteration
+    let code = vec![Nil, Loop(2), Return];
+    assert!(
+      errors(&function(0, code, vec![])).contains(&VerifyErrorKind::InconsistentStackDepth { expected: 1, found: 2 })
+    );
+  }
+
+  #[test]
+  fn test_reject_script() {
+    use OpCode::*;
+    let script = |arity, upvalues| {
+      let mut script = function(arity, vec![Nil, Return], vec![]);
+      script.name = None;
+      script.upvalues = vec![
+        UpvalueDesc {
+          is_local: true,
+          index: 1
+        };
+        upvalues
+      ];
+      script
+    };
+    assert_eq!(errors(&script(0, 0)), []);
+    assert_eq!(errors(&script(1, 0)), [VerifyErrorKind::ScriptArity(1)]);
+    assert_eq!(errors(&script(0, 2)), [VerifyErrorKind::ScriptUpvalues(2)]);
+  }
+}
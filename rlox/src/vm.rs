@@ -1,23 +1,60 @@
+use crate::bytecode;
 use crate::chunk::*;
+use crate::compile::{Compiler, OptLevel};
+use crate::custom_error::{InterpretError, RuntimeError, RuntimeErrorKind, TraceFrame};
+use crate::memory::{Gc, GcConfig, GcStats, Heap, HeapObject};
+use crate::object::{BoundMethod, Class, Closure, Function, Instance, Upvalue};
+use crate::span::{SourceMap, Span};
 use crate::value::Value;
-use std::rc::Rc;
+use crate::verify::verify;
+use std::collections::HashMap;
 
-const MAX_STACK: usize = 255;
-pub struct VM {
-  chunk: Chunk,
-  stack: [Value; MAX_STACK],
+/// The default limit of nested calls, counting the script.
+const FRAMES_MAX: usize = 64;
+/// The default limit of the stack size, each call frame could address at most 256 stack slots.
+pub const MAX_STACK: usize = FRAMES_MAX * 256;
+/// The initial stack size, the stack grows on demand up to the limit.
+const STACK_INIT: usize = 256;
+
+/// The invocation of a function.
+struct CallFrame {
+  closure: Gc<Closure>,
+  /// The function of `closure`, cached to avoid one more indirection on every instruction fetch.
+  function: Gc<Function>,
   /// Aka. `%rip`, which points to the **next** instruction.
   ip: usize,
+  /// The stack index of slot 0 of this frame, where the callee itself is stored.
+  slots: usize,
+}
+
+pub struct VM {
+  frames: Vec<CallFrame>,
+  /// Filled with `Nil` up to its length, so values below the length could be written without checking.
+  stack: Vec<Value>,
+  stack_limit: usize,
+  frame_limit: usize,
   /// Aka. `%rsp`, which points to the **next** postion on stack.
   sp: usize,
+  globals: HashMap<Gc<String>, Value>,
+  /// Upvalues still pointing to stack slots, sorted by the slot index.
+  open_upvalues: Vec<Gc<Upvalue>>,
+  heap: Heap,
+  /// The name of initializers, kept to avoid looking it up on every instantiation.
+  init_string: Gc<String>,
+  opt_level: OptLevel,
+  /// Print each instruction and the stack to stderr before executing it.
+  trace_exec: bool,
+  /// Print the disassembly of compiled functions to stderr.
+  print_code: bool,
+  /// The scripts run so far, which the spans in errors point into.
+  source_map: SourceMap,
 }
 
 macro_rules! binary{
   ($vm:ident, $op: tt, $typ:ident) => {
     {
     if !$vm.peek(0).is_number() || !$vm.peek(1).is_number() {
-      $vm.raise("operand must be numbers".into());
-      return;
+      return Err($vm.binary_type_error("operands must be numbers"));
     }
     let rhs = $vm.pop().as_number().unwrap();
     let lhs = $vm.pop().as_number().unwrap();
@@ -26,14 +63,179 @@ macro_rules! binary{
   }
 }
 
+/// The value returned by the script, or the error stopping it.
+pub type InterpretResult = Result<Value, InterpretError>;
+
 impl VM {
-  pub fn new(chunk: Chunk) -> Self {
+  pub fn new() -> Self {
+    Self::with_gc_config(GcConfig::default())
+  }
+
+  pub fn with_gc_config(config: GcConfig) -> Self {
+    let mut heap = Heap::with_config(config);
+    let init_string = heap.intern("init".into());
     Self {
-      chunk,
-      stack: std::array::from_fn(|_| Value::Nil),
-      ip: 0,
+      frames: Vec::with_capacity(FRAMES_MAX),
+      stack: vec![Value::Nil; STACK_INIT],
+      stack_limit: MAX_STACK,
+      frame_limit: FRAMES_MAX,
       sp: 0,
+      globals: HashMap::new(),
+      open_upvalues: Vec::new(),
+      heap,
+      init_string,
+      opt_level: OptLevel::default(),
+      trace_exec: false,
+      print_code: false,
+      source_map: SourceMap::new(),
+    }
+  }
+
+  /// Set how much the scripts are optimized when compiled.
+  pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+    self.opt_level = opt_level;
+  }
+
+  /// Trace the execution to stderr, this does nothing unless the `trace` feature is enabled.
+  pub fn set_trace_exec(&mut self, trace_exec: bool) {
+    self.trace_exec = trace_exec;
+  }
+
+  /// Print the compiled code to stderr, this does nothing unless the `trace` feature is enabled.
+  pub fn set_print_code(&mut self, print_code: bool) {
+    self.print_code = print_code;
+  }
+
+  /// Set the maximum number of values on the stack, exceeding it raises a stack overflow.  
+  /// The limit is at least 1 to hold the script itself.
+  pub fn set_stack_limit(&mut self, limit: usize) {
+    self.stack_limit = limit.max(1);
+    self.stack.truncate(self.stack_limit);
+  }
+
+  /// Set the maximum number of nested calls, exceeding it raises a stack overflow.  
+  /// The limit is at least 1 to run the script itself.
+  pub fn set_frame_limit(&mut self, limit: usize) {
+    self.frame_limit = limit.max(1);
+  }
+
+  pub fn gc_stats(&self) -> GcStats {
+    self.heap.stats()
+  }
+
+  pub fn source_map(&self) -> &SourceMap {
+    &self.source_map
+  }
+
+  /// Compile `source` and run it. Globals are kept between calls, which is what the REPL relies on.
+  pub fn interpret(&mut self, source: String) -> InterpretResult {
+    self.interpret_file("<script>".into(), source)
+  }
+
+  /// Like `interpret`, with the name of the file `source` is read from.
+  pub fn interpret_file(&mut self, name: String, source: String) -> InterpretResult {
+    let file = self.source_map.add(name, source.clone());
+    let mut compiler = Compiler::with_opt_level(source, &mut self.heap, self.opt_level);
+    compiler.set_file(file);
+    compiler.set_print_code(self.print_code);
+    let script = compiler.compile()?;
+    debug_assert!(
+      verify(&script, &self.heap).is_ok(),
+      "the compiler emits unverifiable code"
+    );
+    Ok(self.run_script(script)?)
+  }
+
+  /// Load a script compiled into the `.loxc` format from the file `name` and run it.
+  pub fn interpret_bytecode(&mut self, name: String, bytes: &[u8]) -> InterpretResult {
+    // the source is not stored in the compiled script
+    let file = self.source_map.add(name, String::new());
+    let script = bytecode::read(bytes, &mut self.heap, file)?;
+    Ok(self.run_script(script)?)
+  }
+
+  fn run_script(&mut self, script: Function) -> Result<Value, RuntimeError> {
+    // The constants of the script are not rooted until the script is on the stack,
+    // so allocate it without the chance to collect.
+    let function = self.heap.alloc(script);
+    self.push(Value::Function(function));
+    let closure = self.alloc(Closure::new(function, 0));
+    self.pop();
+    self.push(Value::Closure(closure));
+    self.call(closure, 0)?;
+    self.run()
+  }
+
+  /// Allocate `object` on the heap, collect garbage first if the heap has grown enough.
+  fn alloc<T: HeapObject>(&mut self, object: T) -> Gc<T> {
+    if self.heap.should_collect() {
+      self.collect_garbage();
+    }
+    self.heap.alloc(object)
+  }
+
+  /// Intern the string `s`, collect garbage first if a new string has to be allocated.
+  fn intern(&mut self, s: String) -> Gc<String> {
+    if let Some(handle) = self.heap.interned(&s) {
+      return handle;
+    }
+    if self.heap.should_collect() {
+      self.collect_garbage();
     }
+    self.heap.intern(s)
+  }
+
+  /// Mark all the roots, then let the heap trace and sweep.
+  fn collect_garbage(&mut self) {
+    for value in &self.stack[..self.sp] {
+      self.heap.mark_value(value);
+    }
+    for frame in &self.frames {
+      self.heap.mark(frame.closure);
+    }
+    for upvalue in &self.open_upvalues {
+      self.heap.mark(*upvalue);
+    }
+    for (name, value) in &self.globals {
+      self.heap.mark(*name);
+      self.heap.mark_value(value);
+    }
+    self.heap.mark(self.init_string);
+    self.heap.collect();
+  }
+
+  /// The frame of the function being executed.
+  fn frame(&self) -> &CallFrame {
+    unsafe { self.frames.last().unwrap_unchecked() }
+  }
+
+  fn frame_mut(&mut self) -> &mut CallFrame {
+    unsafe { self.frames.last_mut().unwrap_unchecked() }
+  }
+
+  /// The chunk of the function being executed.
+  fn chunk(&self) -> &Chunk {
+    &self.heap.get(self.frame().function).chunk
+  }
+
+  fn read_constant(&self, index: usize) -> Value {
+    self.chunk().get_constant(index)
+  }
+
+  /// Read the name the instruction `ins` refers to, which the verifier has checked to be a string.
+  fn read_string(&self, ins: OpCode) -> Gc<String> {
+    self.read_constant(ins.constant_index().unwrap()).as_string().unwrap()
+  }
+
+  /// Grow the stack for one more value, return false if the stack is already at the limit.
+  #[cold]
+  fn grow_stack(&mut self) -> bool {
+    if self.stack.len() >= self.stack_limit {
+      return false;
+    }
+    let len = (self.stack.len() * 2).min(self.stack_limit);
+    self.stack.resize(len, Value::Nil);
+    true
   }
 
   /// Push `value` to stack
@@ -46,7 +248,7 @@ impl VM {
 
   fn pop(&mut self) -> Value {
     self.sp -= 1;
-    unsafe { std::mem::take(self.stack.get_unchecked_mut(self.sp)) }
+    unsafe { *self.stack.get_unchecked(self.sp) }
   }
 
   /// Peek the stack value with `offset` from the stack top.
@@ -54,26 +256,208 @@ impl VM {
     unsafe { self.stack.get_unchecked(self.sp - offset - 1) }
   }
 
-  // Raise a Runtime Error with massage, reset the stack.
-  fn raise(&mut self, msg: String) {
-    eprintln!("RuntimeError: [line {}] {}", self.chunk.get_line_nu(self.ip), msg);
+  /// Build a runtime error with the position and the stack trace, then reset the stack.
+  fn raise(&mut self, kind: RuntimeErrorKind) -> RuntimeError {
+    // `ip` has already moved to the next instruction.
+    // There is no frame when the script itself cannot be called.
+    let span = self.frames.last().map_or_else(Span::default, |frame| {
+      self.heap.get(frame.function).chunk.get_span(frame.ip - 1)
+    });
+    let trace = self
+      .frames
+      .iter()
+      .rev()
+      .map(|frame| {
+        let function = self.heap.get(frame.function);
+        TraceFrame {
+          line: function.chunk.get_line_nu(frame.ip - 1),
+          function: function.name.clone(),
+        }
+      })
+      .collect();
     self.sp = 0;
+    self.frames.clear();
+    self.open_upvalues.clear();
+    RuntimeError { kind, span, trace }
+  }
+
+  /// Call `callee` with `arg_count` arguments on the stack.
+  fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), RuntimeError> {
+    match callee {
+      Value::Closure(closure) => self.call(closure, arg_count),
+      Value::Class(class) => {
+        // replace the class in slot 0 with the new instance, which is `this` in the initializer
+        let instance = self.alloc(Instance::new(class));
+        self.stack[self.sp - arg_count as usize - 1] = Value::Instance(instance);
+        let initializer = self.heap.get(class).methods.get(&self.init_string).copied();
+        match initializer {
+          Some(initializer) => self.call(initializer, arg_count),
+          None if arg_count != 0 => Err(self.raise(RuntimeErrorKind::ArityMismatch {
+            expected: 0,
+            got: arg_count.into(),
+          })),
+          None => Ok(()),
+        }
+      }
+      Value::BoundMethod(bound) => {
+        let bound = self.heap.get(bound);
+        let method = bound.method;
+        self.stack[self.sp - arg_count as usize - 1] = bound.receiver;
+        self.call(method, arg_count)
+      }
+      _ => Err(self.raise(RuntimeErrorKind::NotCallable(callee.type_name()))),
+    }
+  }
+
+  /// Push a new call frame for `closure`, whose arguments are on the top of stack.
+  fn call(&mut self, closure: Gc<Closure>, arg_count: u8) -> Result<(), RuntimeError> {
+    let function = self.heap.get(closure).function;
+    let arity = self.heap.get(function).arity;
+    if arg_count as usize != arity {
+      return Err(self.raise(RuntimeErrorKind::ArityMismatch {
+        expected: arity,
+        got: arg_count.into(),
+      }));
+    }
+    if self.frames.len() >= self.frame_limit {
+      return Err(self.raise(RuntimeErrorKind::StackOverflow));
+    }
+    self.frames.push(CallFrame {
+      closure,
+      function,
+      ip: 0,
+      slots: self.sp - arg_count as usize - 1,
+    });
+    Ok(())
+  }
+
+  /// Invoke the method `name` of the receiver on the stack with `arg_count` arguments.  
+  /// A field holding a callable value shadows the method of the same name.
+  fn invoke(&mut self, name: Gc<String>, arg_count: u8) -> Result<(), RuntimeError> {
+    let Value::Instance(instance) = *self.peek(arg_count as usize) else {
+      return Err(self.unary_type_error(arg_count as usize, "only instances have methods"));
+    };
+    let instance = self.heap.get(instance);
+    if let Some(field) = instance.fields.get(&name).copied() {
+      self.stack[self.sp - arg_count as usize - 1] = field;
+      return self.call_value(field, arg_count);
+    }
+    self.invoke_from_class(instance.class, name, arg_count)
+  }
+
+  fn invoke_from_class(&mut self, class: Gc<Class>, name: Gc<String>, arg_count: u8) -> Result<(), RuntimeError> {
+    let method = self.heap.get(class).methods.get(&name).copied();
+    match method {
+      Some(method) => self.call(method, arg_count),
+      None => Err(self.undefined_property(name)),
+    }
   }
 
-  pub fn run(&mut self) {
+  fn undefined_property(&mut self, name: Gc<String>) -> RuntimeError {
+    let name = self.heap.get(name).clone();
+    self.raise(RuntimeErrorKind::UndefinedProperty(name))
+  }
+
+  /// Replace the instance on the stack top with its method `name` bound to it.
+  fn bind_method(&mut self, class: Gc<Class>, name: Gc<String>) -> Result<(), RuntimeError> {
+    let method = self.heap.get(class).methods.get(&name).copied();
+    match method {
+      Some(method) => {
+        let bound = self.alloc(BoundMethod {
+          receiver: *self.peek(0),
+          method,
+        });
+        self.pop();
+        self.push(Value::BoundMethod(bound));
+        Ok(())
+      }
+      None => Err(self.undefined_property(name)),
+    }
+  }
+
+  /// Raise a type error for the value `distance` slots below the top of the stack.
+  fn unary_type_error(&mut self, distance: usize, expected: &'static str) -> RuntimeError {
+    let found = self.peek(distance).type_name().into();
+    self.raise(RuntimeErrorKind::TypeMismatch { expected, found })
+  }
+
+  /// Raise a type error for the two operands on the top of the stack.
+  fn binary_type_error(&mut self, expected: &'static str) -> RuntimeError {
+    let found = format!("{} and {}", self.peek(1).type_name(), self.peek(0).type_name());
+    self.raise(RuntimeErrorKind::TypeMismatch { expected, found })
+  }
+
+  fn undefined_variable(&mut self, name: Gc<String>) -> RuntimeError {
+    let name = self.heap.get(name).clone();
+    self.raise(RuntimeErrorKind::UndefinedVariable(name))
+  }
+
+  /// The stack slot an open upvalue points to.
+  fn open_slot(&self, upvalue: Gc<Upvalue>) -> usize {
+    match self.heap.get(upvalue) {
+      Upvalue::Open(slot) => *slot,
+      Upvalue::Closed(_) => unreachable!("closed upvalue in open list"),
+    }
+  }
+
+  /// Return the upvalue pointing to stack `slot`, create one if no closure has captured it yet.
+  fn capture_upvalue(&mut self, slot: usize) -> Gc<Upvalue> {
+    let position = self
+      .open_upvalues
+      .binary_search_by_key(&slot, |upvalue| self.open_slot(*upvalue));
+    match position {
+      Ok(i) => self.open_upvalues[i],
+      Err(i) => {
+        let upvalue = self.alloc(Upvalue::Open(slot));
+        self.open_upvalues.insert(i, upvalue);
+        upvalue
+      }
+    }
+  }
+
+  /// Close every open upvalue pointing to a stack slot at or above `last`,
+  /// by moving the variable from the stack into the upvalue.
+  fn close_upvalues(&mut self, last: usize) {
+    while let Some(&upvalue) = self.open_upvalues.last() {
+      let slot = self.open_slot(upvalue);
+      if slot < last {
+        break;
+      }
+      *self.heap.get_mut(upvalue) = Upvalue::Closed(self.stack[slot]);
+      self.open_upvalues.pop();
+    }
+  }
+
+  /// Execute the frames on the call stack, return the value returned by the script.
+  fn run(&mut self) -> Result<Value, RuntimeError> {
     use OpCode::*;
     let mut ins;
-    println!("== RUNNING VM ==");
     loop {
-      ins = self.chunk.fetch(self.ip);
-      println!("EXECUING INSTRUCTION: {}", ins);
+      ins = self.chunk().fetch(self.frame().ip);
+      #[cfg(feature = "trace")]
+      if self.trace_exec {
+        self.trace_instruction(ins);
+      }
+      self.frame_mut().ip += 1;
+      // No instruction pushes more values than it pops by more than one, so checking the room for one value
+      // before each instruction is enough for `push` to go unchecked.
+      if self.sp == self.stack.len() && !self.grow_stack() {
+        return Err(self.raise(RuntimeErrorKind::StackOverflow));
+      }
       match ins {
         Return => {
-          println!("{}\n", self.pop());
-          return;
+          let result = self.pop();
+          self.close_upvalues(self.frame().slots);
+          let frame = self.frames.pop().unwrap();
+          // drop the callee with its arguments and locals, for the script this empties the stack
+          self.sp = frame.slots;
+          if self.frames.is_empty() {
+            return Ok(result);
+          }
+          self.push(result);
         }
-        Constant(val) => {
-          let constant = self.chunk.get_constant(val.into());
+        Constant(_) | ConstantLong(_) => {
+          let constant = self.read_constant(ins.constant_index().unwrap());
           self.push(constant);
         }
         Neg => match self.peek(0) {
@@ -81,10 +465,7 @@ impl VM {
             let n = self.pop().as_number().unwrap();
             self.push(Value::Number(-n));
           }
-          _ => {
-            self.raise("oprand must be number".into());
-            return;
-          }
+          _ => return Err(self.unary_type_error(0, "operand must be a number")),
         },
         Not => {
           let a = self.pop().is_false();
@@ -99,9 +480,16 @@ impl VM {
             let lhs = self.pop().as_number().unwrap();
             self.push(Value::Number(lhs + rhs));
           } else if self.peek(0).is_string() && self.peek(1).is_string() {
-            let rhs = (*self.pop().as_string().unwrap()).clone();
-            let lhs = (*self.pop().as_string().unwrap()).clone();
-            self.push(Value::Str(Rc::new(lhs + &rhs)))
+            let rhs = self.heap.get(self.peek(0).as_string().unwrap());
+            let lhs = self.heap.get(self.peek(1).as_string().unwrap());
+            let result = format!("{}{}", lhs, rhs);
+            // the operands are kept on the stack during allocation, in case of a collection
+            let result = self.intern(result);
+            self.pop();
+            self.pop();
+            self.push(Value::Str(result))
+          } else {
+            return Err(self.binary_type_error("operands must be two numbers or two strings"));
           }
         }
         Sub => binary!(self, -, Number),
@@ -114,16 +502,575 @@ impl VM {
           let lhs = self.pop();
           self.push(Value::Boolean(lhs.equals(&rhs)));
         }
+        NotEqual => {
+          let rhs = self.pop();
+          let lhs = self.pop();
+          self.push(Value::Boolean(!lhs.equals(&rhs)));
+        }
+        // Defined as `!(a < b)` and `!(a > b)` rather than `a >= b` and `a <= b`,
+        // so NaN compares the same as the unoptimized `Less`/`Greater` followed by `Not`.
+        GreaterEqual => {
+          binary!(self, <, Boolean);
+          let result = self.pop().is_false();
+          self.push(Value::Boolean(result));
+        }
+        LessEqual => {
+          binary!(self, >, Boolean);
+          let result = self.pop().is_false();
+          self.push(Value::Boolean(result));
+        }
+        Print => {
+          let value = self.pop();
+          println!("{}", value.to_string(&self.heap));
+        }
+        Pop => {
+          self.pop();
+        }
+        DefineGlobal(_) | DefineGlobalLong(_) => {
+          let name = self.read_string(ins);
+          let value = self.pop();
+          self.globals.insert(name, value);
+        }
+        GetGlobal(_) | GetGlobalLong(_) => {
+          let name = self.read_string(ins);
+          match self.globals.get(&name) {
+            Some(value) => self.push(*value),
+            None => return Err(self.undefined_variable(name)),
+          }
+        }
+        SetGlobal(_) | SetGlobalLong(_) => {
+          let name = self.read_string(ins);
+          // assignment is an expression, so leave the value on the stack
+          let value = *self.peek(0);
+          match self.globals.get_mut(&name) {
+            Some(global) => *global = value,
+            None => return Err(self.undefined_variable(name)),
+          }
+        }
+        GetLocal(slot) => {
+          let slot = self.frame().slots + slot as usize;
+          let value = unsafe { *self.stack.get_unchecked(slot) };
+          self.push(value);
+        }
+        SetLocal(slot) => {
+          let slot = self.frame().slots + slot as usize;
+          let value = *self.peek(0);
+          unsafe {
+            *self.stack.get_unchecked_mut(slot) = value;
+          }
+        }
+        Jump(offset) => self.frame_mut().ip += offset as usize,
+        JumpIfFalse(offset) => {
+          if self.peek(0).is_false() {
+            self.frame_mut().ip += offset as usize;
+          }
+        }
+        // `ip` has already moved past the `Loop`, so this lands exactly on the loop start.
+        Loop(offset) => self.frame_mut().ip -= offset as usize,
+        Closure(_) | ClosureLong(_) => {
+          let function = self.read_constant(ins.constant_index().unwrap()).as_function().unwrap();
+          let upvalues = self.heap.get(function).upvalues.clone();
+          let mut closure = crate::object::Closure::new(function, upvalues.len());
+          for upvalue in upvalues {
+            let captured = if upvalue.is_local {
+              self.capture_upvalue(self.frame().slots + upvalue.index as usize)
+            } else {
+              self.heap.get(self.frame().closure).upvalues[upvalue.index as usize]
+            };
+            closure.upvalues.push(captured);
+          }
+          let closure = self.alloc(closure);
+          self.push(Value::Closure(closure));
+        }
+        GetUpvalue(i) => {
+          let upvalue = self.heap.get(self.frame().closure).upvalues[i as usize];
+          let value = match self.heap.get(upvalue) {
+            Upvalue::Open(slot) => self.stack[*slot],
+            Upvalue::Closed(value) => *value,
+          };
+          self.push(value);
+        }
+        SetUpvalue(i) => {
+          let value = *self.peek(0);
+          let upvalue = self.heap.get(self.frame().closure).upvalues[i as usize];
+          match self.heap.get_mut(upvalue) {
+            Upvalue::Open(slot) => self.stack[*slot] = value,
+            Upvalue::Closed(closed) => *closed = value,
+          }
+        }
+        CloseUpvalue => {
+          self.close_upvalues(self.sp - 1);
+          self.pop();
+        }
+        Call(arg_count) => {
+          let callee = *self.peek(arg_count as usize);
+          self.call_value(callee, arg_count)?;
+        }
+        Class(_) | ClassLong(_) => {
+          let name = self.heap.get(self.read_string(ins)).clone();
+          let class = self.alloc(crate::object::Class::new(name));
+          self.push(Value::Class(class));
+        }
+        GetProperty(_) | GetPropertyLong(_) => {
+          let Value::Instance(instance) = *self.peek(0) else {
+            return Err(self.unary_type_error(0, "only instances have properties"));
+          };
+          let name = self.read_string(ins);
+          let instance = self.heap.get(instance);
+          if let Some(value) = instance.fields.get(&name).copied() {
+            self.pop();
+            self.push(value);
+          } else {
+            self.bind_method(instance.class, name)?;
+          }
+        }
+        SetProperty(_) | SetPropertyLong(_) => {
+          let Value::Instance(instance) = *self.peek(1) else {
+            return Err(self.unary_type_error(1, "only instances have fields"));
+          };
+          let name = self.read_string(ins);
+          let value = self.pop();
+          self.heap.get_mut(instance).fields.insert(name, value);
+          // pop the instance, leave the assigned value as the result
+          self.pop();
+          self.push(value);
+        }
+        Method(_) | MethodLong(_) => {
+          let name = self.read_string(ins);
+          let Value::Closure(method) = *self.peek(0) else {
+            return Err(self.unary_type_error(0, "method must be a function"));
+          };
+          let Value::Class(class) = *self.peek(1) else {
+            return Err(self.unary_type_error(1, "methods are defined on a class"));
+          };
+          self.pop();
+          self.heap.get_mut(class).methods.insert(name, method);
+        }
+        Invoke(_, arg_count) | InvokeLong(_, arg_count) => {
+          let name = self.read_string(ins);
+          self.invoke(name, arg_count)?;
+        }
+        Inherit => {
+          let Value::Class(superclass) = *self.peek(1) else {
+            return Err(self.unary_type_error(1, "superclass must be a class"));
+          };
+          // copy-down inheritance, methods defined later in the subclass override the copied ones
+          let Value::Class(subclass) = *self.peek(0) else {
+            return Err(self.unary_type_error(0, "subclass must be a class"));
+          };
+          let methods = self.heap.get(superclass).methods.clone();
+          self.pop();
+          self.heap.get_mut(subclass).methods.extend(methods);
+        }
+        GetSuper(_) | GetSuperLong(_) => {
+          let name = self.read_string(ins);
+          let Value::Class(superclass) = *self.peek(0) else {
+            return Err(self.unary_type_error(0, "superclass must be a class"));
+          };
+          self.pop();
+          self.bind_method(superclass, name)?;
+        }
+        SuperInvoke(_, arg_count) | SuperInvokeLong(_, arg_count) => {
+          let name = self.read_string(ins);
+          let Value::Class(superclass) = *self.peek(0) else {
+            return Err(self.unary_type_error(0, "superclass must be a class"));
+          };
+          self.pop();
+          self.invoke_from_class(superclass, name, arg_count)?;
+        }
       }
-      self.ip += 1;
-      println!("== STACK ==");
-      if self.sp == 0 {
-        print!("EMPTY");
+    }
+  }
+
+  /// Print the instruction about to be executed to stderr, as `exec\tip\tline\tOPCODE\toperands\tstack`
+  /// where the stack lists the values from the bottom, each as `[ value ]`.
+  #[cfg(feature = "trace")]
+  fn trace_instruction(&self, ins: OpCode) {
+    let ip = self.frame().ip;
+    let stack: String = self.stack[..self.sp]
+      .iter()
+      .map(|value| format!("[ {} ]", value.to_quoted_string(&self.heap)))
+      .collect();
+    let chunk = self.chunk();
+    let operands = chunk.operands(&ins, &self.heap);
+    eprintln!(
+      "exec\t{}\t{}\t{}\t{}\t{}",
+      ip,
+      chunk.get_line_nu(ip),
+      ins.name(),
+      operands,
+      stack
+    );
+  }
+}
+
+#[cfg(test)]
+mod vm_test {
+  use super::*;
+
+  const PROGRAM: &str = "
+    fun makeCounter() { var i = 0; fun count() { i = i + 1; return i; } return count; }
+    var counter = makeCounter();
+    class A { init(name) { this.name = name; } hello() { return \"hello \" + this.name; } }
+    class B < A { hello() { return super.hello() + \"!\"; } }
+    for (var i = 0; i < 10; i = i + 1) {
+      var b = B(\"b\" + \"c\");
+      var hello = b.hello;
+      print hello() + \"\" + \"\";
+      counter();
+    }
+    print counter();
+  ";
+
+  #[test]
+  fn test_stress_gc() {
+    let mut vm = VM::with_gc_config(GcConfig {
+      stress: true,
+      ..GcConfig::default()
+    });
+    vm.interpret(PROGRAM.into()).unwrap();
+    let stats = vm.gc_stats();
+    assert!(stats.collections > 0);
+    assert!(stats.bytes_freed > 0);
+    assert!(stats.bytes_freed <= stats.bytes_allocated);
+  }
+
+  #[test]
+  fn test_garbage_is_freed() {
+    let mut vm = VM::with_gc_config(GcConfig {
+      initial_threshold: 4096,
+      ..GcConfig::default()
+    });
+    let source = "class P {} for (var i = 0; i < 1000; i = i + 1) { var p = P(); p.s = \"s\" + \"t\"; }";
+    vm.interpret(source.into()).unwrap();
+    // only a small portion of the 1000 instances and strings could survive
+    assert!(vm.heap.object_count() < 500);
+    assert!(vm.gc_stats().collections > 0);
+  }
+
+  #[test]
+  fn test_string_interning() {
+    let mut vm = VM::new();
+    let source = "var a = \"con\" + \"cat\"; var b = \"concat\"; var same = a == b; var diff = a == \"cat\";";
+    vm.interpret(source.into()).unwrap();
+    let global = |name: &str| vm.globals[&vm.heap.interned(name).unwrap()];
+    assert!(global("a").as_string().unwrap() == global("b").as_string().unwrap());
+    assert_eq!(global("same").as_bool(), Some(true));
+    assert_eq!(global("diff").as_bool(), Some(false));
+  }
+
+  #[test]
+  fn test_wide_constant() {
+    let mut vm = VM::new();
+    vm.set_opt_level(OptLevel::O0);
+    let sum = (0..300).map(|i| i.to_string()).collect::<Vec<_>>().join(" + ");
+    vm.interpret(format!("var sum = {};", sum)).unwrap();
+    let result = vm.globals[&vm.heap.interned("sum").unwrap()];
+    assert_eq!(result.as_number(), Some((0..300).sum::<i32>() as f64));
+
+    // names beyond the first 256 constants
+    let source = format!(
+      "var s = {}; var t = s; class A {{ m(x) {{ this.f = x; return this.f; }} }} \
+       class B < A {{ m(x) {{ var m = super.m; return m(x) + super.m(x); }} }} var r = B().m(t);",
+      sum
+    );
+    vm.interpret(source).unwrap();
+    let r = vm.globals[&vm.heap.interned("r").unwrap()];
+    assert_eq!(r.as_number(), Some(2.0 * (0..300).sum::<i32>() as f64));
+  }
+
+  #[test]
+  fn test_opt_level() {
+    let source = "var nan = 0 / 0; var a = 1 + (2 + 3) * 4; var b = \"a\" + \"b\" == \"ab\"; \
+                  var c = !(1 <= 2); var d = nan >= 1; var e = nan <= nan; var f = 1 != nil; var g = -2 - -3;";
+    let run = |opt_level| {
+      let mut vm = VM::new();
+      vm.set_opt_level(opt_level);
+      vm.interpret(source.into()).unwrap();
+      ["a", "b", "c", "d", "e", "f", "g"].map(|name| vm.globals[&vm.heap.interned(name).unwrap()].to_string(&vm.heap))
+    };
+    let optimized = run(OptLevel::O1);
+    assert_eq!(optimized, ["21", "true", "false", "true", "true", "true", "1"]);
+    assert_eq!(run(OptLevel::O0), optimized);
+  }
+
+  #[test]
+  fn test_stack_overflow() {
+    // 100 locals, then every nested operand stays on the stack without folding
+    let locals = (0..100).map(|i| format!("var l{} = 1;", i)).collect::<String>();
+    let nested = format!(
+      "var x; {{ {} x = {}1{}; }}",
+      locals,
+      "(1 + ".repeat(200),
+      ")".repeat(200)
+    );
+    let run = |limit| {
+      let mut vm = VM::new();
+      vm.set_opt_level(OptLevel::O0);
+      vm.set_stack_limit(limit);
+      vm.interpret(nested.clone())
+        .map(|_| vm.globals[&vm.heap.interned("x").unwrap()])
+    };
+    // the stack grows beyond its initial size
+    assert_eq!(run(MAX_STACK).unwrap().as_number(), Some(201.0));
+    match run(STACK_INIT) {
+      Err(InterpretError::Runtime(e)) => assert_eq!(e.kind, RuntimeErrorKind::StackOverflow),
+      other => panic!("expected a stack overflow, got {:?}", other.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn test_frame_limit() {
+    let source = "fun f(n) { if (n > 0) return f(n - 1); return 0; } print f(100);";
+    let run = |limit| {
+      let mut vm = VM::new();
+      vm.set_frame_limit(limit);
+      vm.interpret(source.into())
+    };
+    // the script and 101 calls of `f`
+    assert!(run(102).is_ok());
+    match run(101) {
+      Err(InterpretError::Runtime(e)) => assert_eq!(e.kind, RuntimeErrorKind::StackOverflow),
+      other => panic!("expected a stack overflow, got {:?}", other.map(|_| ())),
+    }
+    assert!(run(FRAMES_MAX).is_err());
+  }
+
+  fn runtime_error(source: &str) -> RuntimeError {
+    match VM::new().interpret(source.into()) {
+      Err(InterpretError::Runtime(e)) => e,
+      other => panic!("expected a runtime error, got {:?}", other.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn test_runtime_error() {
+    let e = runtime_error("print 1;\nprint x;");
+    assert_eq!(e.kind, RuntimeErrorKind::UndefinedVariable("x".into()));
+    assert_eq!((e.span.line, e.span.column), (2, 7));
+
+    let e = runtime_error("fun f(a) {}\nf(1, 2);");
+    assert_eq!(e.kind, RuntimeErrorKind::ArityMismatch { expected: 1, got: 2 });
+    assert_eq!(
+      runtime_error("var a = 1; a();").kind,
+      RuntimeErrorKind::NotCallable("number")
+    );
+    assert_eq!(
+      runtime_error("class A {} A().b;").kind,
+      RuntimeErrorKind::UndefinedProperty("b".into())
+    );
+    assert!(matches!(
+      runtime_error("-nil;").kind,
+      RuntimeErrorKind::TypeMismatch { .. }
+    ));
+  }
+
+  #[test]
+  fn test_stack_trace() {
+    let e = runtime_error("fun inner() {\n  return nil * 1;\n}\nfun outer() {\n  inner();\n}\nouter();");
+    let trace = [(2, Some("inner")), (5, Some("outer")), (7, None)];
+    let found: Vec<_> = e
+      .trace
+      .iter()
+      .map(|frame| (frame.line, frame.function.as_deref()))
+      .collect();
+    assert_eq!(found, trace);
+    assert_eq!(
+      e.to_string(),
+      "RuntimeError: [line 2, column 14] operands must be numbers, got nil and number\n[line 2] in inner()\n[line 5] in outer()\n[line 7] in script"
+    );
+  }
+
+  /// Operands of every type, with their type names.
+  const OPERANDS: [(&str, &str); 7] = [
+    ("1", "number"),
+    ("\"s\"", "string"),
+    ("true", "boolean"),
+    ("nil", "nil"),
+    ("f", "function"),
+    ("A", "class"),
+    ("A()", "instance"),
+  ];
+
+  /// Run `expr` at both optimization levels, return the error message if any.
+  fn type_check(expr: &str) -> Option<String> {
+    let source = format!("fun f() {{}} class A {{}} var r = {};", expr);
+    let run = |opt_level| {
+      let mut vm = VM::new();
+      vm.set_opt_level(opt_level);
+      match vm.interpret(source.clone()) {
+        Ok(_) => None,
+        Err(InterpretError::Runtime(e)) => Some(e.kind.to_string()),
+        Err(e) => panic!("{}", e),
       }
-      for i in 0..self.sp {
-        print!("[ {} ]", unsafe { self.stack.get_unchecked(i) })
+    };
+    let result = run(OptLevel::O0);
+    assert_eq!(run(OptLevel::O1), result, "{}", expr);
+    result
+  }
+
+  #[test]
+  fn test_binary_type_check() {
+    for (lhs, lhs_type) in OPERANDS {
+      for (rhs, rhs_type) in OPERANDS {
+        let found = format!("{} and {}", lhs_type, rhs_type);
+        let numbers = lhs_type == "number" && rhs_type == "number";
+        let add = match numbers || lhs_type == "string" && rhs_type == "string" {
+          true => None,
+          false => Some(format!("operands must be two numbers or two strings, got {}", found)),
+        };
+        assert_eq!(type_check(&format!("{} + {}", lhs, rhs)), add);
+        for op in ["-", "*", "/", ">", "<", ">=", "<="] {
+          let expected = match numbers {
+            true => None,
+            false => Some(format!("operands must be numbers, got {}", found)),
+          };
+          assert_eq!(type_check(&format!("{} {} {}", lhs, op, rhs)), expected);
+        }
+        for op in ["==", "!="] {
+          assert_eq!(type_check(&format!("{} {} {}", lhs, op, rhs)), None);
+        }
       }
-      println!();
     }
   }
+
+  #[test]
+  fn test_unary_type_check() {
+    for (operand, typ) in OPERANDS {
+      let neg = (typ != "number").then(|| format!("operand must be a number, got {}", typ));
+      assert_eq!(type_check(&format!("-{}", operand)), neg);
+      assert_eq!(type_check(&format!("!{}", operand)), None);
+
+      let instance = typ == "instance";
+      let expected = |msg| (!instance).then(|| format!("only instances have {}, got {}", msg, typ));
+      // `A()` has no field or method named `x`, but the error must be about the type first
+      let get = type_check(&format!("({}).x", operand));
+      assert_eq!(get.filter(|_| !instance), expected("properties"));
+      assert_eq!(type_check(&format!("({}).x = 1", operand)), expected("fields"));
+      let invoke = type_check(&format!("({}).x()", operand));
+      assert_eq!(invoke.filter(|_| !instance), expected("methods"));
+
+      let callable = matches!(typ, "function" | "class");
+      let call = (!callable).then(|| format!("can only call functions and classes, got {}", typ));
+      assert_eq!(type_check(&format!("({})()", operand)), call);
+    }
+    let e = runtime_error("var B = 1; class C < B {}");
+    assert_eq!(e.kind.to_string(), "superclass must be a class, got number");
+  }
+
+  #[test]
+  fn test_error_span() {
+    let mut vm = VM::new();
+    vm.interpret_file("a.lox".into(), "var a = 1;".into()).ok();
+    let e = vm
+      .interpret_file("b.lox".into(), "print a;\nprint a + nil;".into())
+      .err()
+      .unwrap();
+    let span = e.span().unwrap();
+    assert_eq!(vm.source_map().location(span), "b.lox:2:9");
+    assert_eq!(vm.source_map().line_text(span), Some("print a + nil;"));
+    // operators are emitted after their operands, but point at the operator
+    for (source, location) in [("print 1 < 2 < 3;", "d.lox:1:13"), ("print -\"a\";", "d.lox:1:7")] {
+      let e = vm.interpret_file("d.lox".into(), source.into()).err().unwrap();
+      assert_eq!(vm.source_map().location(e.span().unwrap()), location, "{}", source);
+    }
+
+    let e = vm.interpret_file("c.lox".into(), "print (1;".into()).err().unwrap();
+    assert!(matches!(e, InterpretError::Compile(_)));
+    assert_eq!(vm.source_map().location(e.span().unwrap()), "c.lox:1:9");
+  }
+
+  #[test]
+  fn test_escape_error() {
+    let mut vm = VM::new();
+    for source in ["print \"a\\q\";", "print 1;\n\"\\q\""] {
+      let e = vm.interpret(source.into()).err().unwrap();
+      let span = *e.span().unwrap();
+      assert!(matches!(e, InterpretError::Compile(_)));
+      assert_eq!(&vm.source_map().source(span.file)[span.start..span.end], "\\q");
+    }
+  }
+
+  #[test]
+  fn test_number_literal() {
+    let mut vm = VM::new();
+    vm.interpret("var n = 0xFF + 0b1010 + 0o17 + 1_000 + 2.5e-1;".into())
+      .unwrap();
+    let n = vm.globals[&vm.heap.interned("n").unwrap()];
+    assert_eq!(n.as_number(), Some(255.0 + 10.0 + 15.0 + 1000.0 + 0.25));
+    for source in ["print 1;\n1__0", "print 0x;", "var x = 1e;"] {
+      let e = vm.interpret(source.into()).err().unwrap();
+      assert!(matches!(e, InterpretError::Compile(_)), "{}", source);
+    }
+  }
+
+  #[test]
+  fn test_crafted_bytecode() {
+    use crate::chunk::OpCode::*;
+    let mut vm = VM::new();
+    let script = Compiler::new("print 1;".into(), &mut vm.heap).compile().unwrap();
+    let mut bytes = bytecode::write(&script, &vm.heap);
+    // the arity right after the magic, the version and the name tag
+    bytes[9] = 1;
+    let e = vm.interpret_bytecode("test.loxc".into(), &bytes).err().unwrap();
+    assert!(matches!(e, InterpretError::Bytecode(_)), "{}", e);
+
+    // the verifier does not track value types, so these are caught when executed
+    let name = Value::Str(vm.heap.intern("m".into()));
+    for code in [
+      vec![Nil, Nil, Method(0)],
+      vec![Nil, Nil, Inherit],
+      vec![Nil, Nil, GetSuper(0)],
+      vec![Nil, Nil, SuperInvoke(0, 0)],
+    ] {
+      let mut script = Function::new(None);
+      let lines = vec![LineRun {
+        span: Span::default(),
+        count: code.len() + 2,
+      }];
+      script.chunk = Chunk::from_parts([code.clone(), vec![Nil, Return]].concat(), vec![name], lines);
+      let bytes = bytecode::write(&script, &vm.heap);
+      let e = vm.interpret_bytecode("test.loxc".into(), &bytes).err().unwrap();
+      let InterpretError::Runtime(e) = e else {
+        panic!("{}: {}", code[2].name(), e);
+      };
+      assert!(
+        matches!(e.kind, RuntimeErrorKind::TypeMismatch { .. }),
+        "{}: {}",
+        code[2].name(),
+        e
+      );
+    }
+
+    // values left on the stack by the script are dropped when it returns
+    let mut script = Function::new(None);
+    let lines = vec![LineRun {
+      span: Span::default(),
+      count: 4,
+    }];
+    script.chunk = Chunk::from_parts(vec![Nil, Nil, True, Return], vec![], lines);
+    let bytes = bytecode::write(&script, &vm.heap);
+    assert!(matches!(
+      vm.interpret_bytecode("test.loxc".into(), &bytes),
+      Ok(Value::Boolean(true))
+    ));
+    assert_eq!(vm.sp, 0);
+
+    // a script that cannot even be called has no frame to point into
+    let mut script = Function::new(None);
+    script.arity = 1;
+    script.chunk = Chunk::from_parts(vec![Nil, Return], vec![], vec![]);
+    let e = vm.run_script(script).err().unwrap();
+    assert_eq!(e.kind, RuntimeErrorKind::ArityMismatch { expected: 1, got: 0 });
+    assert_eq!(e.span, Span::default());
+  }
+
+  #[test]
+  fn test_script_result() {
+    let mut vm = VM::new();
+    assert!(matches!(vm.interpret("print 1;".into()), Ok(Value::Nil)));
+    // the VM can run again after an error
+    assert!(vm.interpret("print x;".into()).is_err());
+    assert!(vm.interpret("var x = 1;".into()).is_ok());
+  }
 }